@@ -4,7 +4,9 @@ import React, {
     createContext,
     ReactNode,
     useContext,
+    useEffect,
     useMemo,
+    useRef,
     useState,
 } from "react";
 import {Actor, HttpAgent} from "@dfinity/agent";
@@ -22,8 +24,49 @@ export interface CrawlResult {
     pages_with_matching_images: string[];
     visually_similar_images: string[];
     last_update: number;
+    consent_revoked_at?: number | null;
+    // Detection provider that produced the result, e.g. "ShootifyProxy"
+    source?: string | null;
+    provider_job_id?: string | null;
+    // Set for crawls merged from several providers
+    sources?: string[] | null;
+    // Every match in detail; the URL lists above are derived from these
+    matches?: Match[] | null;
 }
 
+export interface Match {
+    url: string;
+    page_url?: string | null;
+    page_title?: string | null;
+    thumbnail_url?: string | null;
+    match_type: "FullMatch" | "PageMatch" | "VisuallySimilar";
+    score?: number | null;
+    first_seen: number;
+    last_seen: number;
+    domain: string;
+    sources: string[];
+}
+
+// Candid variant with a single key naming the kind of error, e.g.
+// { AccessDenied: { message } } or { InvalidArgument: { field, message } }
+export type SentinelError = {
+    [kind: string]: { message: string; field?: string; code?: number };
+};
+
+export const errorMessage = (error?: SentinelError): string =>
+    error ? Object.values(error)[0]?.message ?? "Unknown error" : "Unknown error";
+
+interface CrawlJob {
+    status: { Queued?: null; Running?: null; Succeeded?: null; Failed?: null };
+    result: [] | [string];
+    error: [] | [string];
+}
+
+const CRAWL_JOB_POLL_INTERVAL_MS = 2000;
+// Covers the canister's retries with backoff; a job still pending after this
+// keeps running, and its result shows up with the stored results
+const CRAWL_JOB_POLL_TIMEOUT_MS = 10 * 60 * 1000;
+
 export interface CrawledResults {
     [imageName: string]: CrawlResult;
 }
@@ -41,7 +84,8 @@ interface IcpContextType {
         userId: string,
         predictionId: string,
         imageName: string,
-        imageContent: number[]
+        imageContent: number[],
+        subjectId: string
     ) => Promise<CrawlResult>;
     crawlImageNotStored: (
         userId: string,
@@ -59,6 +103,15 @@ export const IcpProvider: React.FC<{ children: ReactNode }> = ({
     const agent = new HttpAgent({host: ICP_HOST});
     const [crawlingIds, setCrawlingIds] = useState<string[]>([]);
     const [crawledResults, setCrawledResults] = useState<CrawledResults>({});
+    // Polling stops once the provider unmounts
+    const mountedRef = useRef(true);
+
+    useEffect(() => {
+        mountedRef.current = true;
+        return () => {
+            mountedRef.current = false;
+        };
+    }, []);
 
     if (process.env.NEXT_PUBLIC_DFX_NETWORK !== "ic") {
         agent.fetchRootKey().catch((err) => {
@@ -96,7 +149,7 @@ export const IcpProvider: React.FC<{ children: ReactNode }> = ({
                 imageNames.map(async (name) => {
                     const imageResult = (await backendActor.get_image(userId, name)) as {
                         Ok?: { content: number[] };
-                        Err?: string;
+                        Err?: SentinelError;
                     };
 
                     if (imageResult.Ok?.content) {
@@ -120,7 +173,7 @@ export const IcpProvider: React.FC<{ children: ReactNode }> = ({
                             }
                         );
                     } else {
-                        console.error(`Error fetching image '${name}':`, imageResult.Err);
+                        console.error(`Error fetching image '${name}':`, errorMessage(imageResult.Err));
                         return null;
                     }
                 })
@@ -140,14 +193,14 @@ export const IcpProvider: React.FC<{ children: ReactNode }> = ({
         try {
             const storedResults = (await backendActor.get_crawl_results(userId)) as {
                 Ok?: string;
-                Err?: string;
+                Err?: SentinelError;
             };
 
             if (storedResults.Ok) {
                 const parsedStoredResults = JSON.parse(storedResults.Ok);
                 setCrawledResults(parsedStoredResults);
             } else {
-                console.error("Error loading stored results:", storedResults.Err);
+                console.error("Error loading stored results:", errorMessage(storedResults.Err));
             }
         } catch (error) {
             console.error("Error loading stored results:", error);
@@ -158,7 +211,9 @@ export const IcpProvider: React.FC<{ children: ReactNode }> = ({
         userId: string,
         predictionId: string,
         imageName: string,
-        imageContent: number[]
+        imageContent: number[],
+        // Subject depicted in the image; its consent is checked on upload
+        subjectId: string
     ): Promise<CrawlResult> => {
         setCrawlingIds((prev) => [...prev, predictionId]);
         try {
@@ -169,29 +224,59 @@ export const IcpProvider: React.FC<{ children: ReactNode }> = ({
                     userId,
                     predictionId,
                     imageName,
-                    imageContent
-                )) as { Ok?: string; Err?: string };
+                    imageContent,
+                    [subjectId]
+                )) as { Ok?: string; Err?: SentinelError };
 
                 if (storeResult.Err) {
-                    throw new Error(`Failed to store image: ${storeResult.Err}`);
+                    throw new Error(`Failed to store image: ${errorMessage(storeResult.Err)}`);
                 }
             }
 
-            const detectionResult = (await backendActor.detect_image(
+            const enqueueResult = (await backendActor.enqueue_crawl(
                 userId,
                 predictionId,
-                imageName
-            )) as { Ok?: string; Err?: string };
+                imageName,
+                [] // default detection provider
+            )) as { Ok?: bigint; Err?: SentinelError };
 
-            if (detectionResult.Ok) {
-                const parsedResult = JSON.parse(detectionResult.Ok);
-                setCrawledResults((prevResults) => ({
-                    ...prevResults,
-                    [imageName]: parsedResult,
-                }));
-                return parsedResult;
-            } else {
-                throw new Error(`Failed to detect image: ${detectionResult.Err}`);
+            if (enqueueResult.Ok === undefined) {
+                throw new Error(`Failed to detect image: ${errorMessage(enqueueResult.Err)}`);
+            }
+
+            // The crawl runs in the background; poll the job until it finishes
+            const deadline = Date.now() + CRAWL_JOB_POLL_TIMEOUT_MS;
+            while (true) {
+                await new Promise((resolve) =>
+                    setTimeout(resolve, CRAWL_JOB_POLL_INTERVAL_MS)
+                );
+                if (!mountedRef.current) {
+                    throw new Error("Stopped waiting for crawl job: the page was closed.");
+                }
+                if (Date.now() > deadline) {
+                    throw new Error(
+                        "Crawl job is still pending; its result will appear with the stored results."
+                    );
+                }
+                const jobResult = (await backendActor.get_crawl_job(
+                    userId,
+                    enqueueResult.Ok
+                )) as { Ok?: CrawlJob; Err?: SentinelError };
+
+                if (!jobResult.Ok) {
+                    throw new Error(`Failed to detect image: ${errorMessage(jobResult.Err)}`);
+                }
+                if ("Succeeded" in jobResult.Ok.status) {
+                    const parsedResult = JSON.parse(jobResult.Ok.result[0] ?? "{}");
+                    setCrawledResults((prevResults) => ({
+                        ...prevResults,
+                        [imageName]: parsedResult,
+                    }));
+                    return parsedResult;
+                }
+                if ("Failed" in jobResult.Ok.status) {
+                    throw new Error(`Failed to detect image: ${jobResult.Ok.error[0]}`);
+                }
             }
         } catch (error) {
             console.error("Error crawling image:", error);
@@ -213,8 +298,9 @@ export const IcpProvider: React.FC<{ children: ReactNode }> = ({
                 userId,
                 predictionId,
                 imageName,
-                imageContent
-            )) as { Ok?: string; Err?: string };
+                imageContent,
+                [] // default detection provider
+            )) as { Ok?: string; Err?: SentinelError };
 
             if (detectionResult.Ok) {
                 const parsedResult = JSON.parse(detectionResult.Ok);
@@ -224,7 +310,7 @@ export const IcpProvider: React.FC<{ children: ReactNode }> = ({
                 }));
                 return parsedResult;
             } else {
-                throw new Error(`Failed to detect image: ${detectionResult.Err}`);
+                throw new Error(`Failed to detect image: ${errorMessage(detectionResult.Err)}`);
             }
         } catch (error) {
             console.error("Error crawling image:", error);
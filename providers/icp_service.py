@@ -46,9 +46,28 @@ class GovernanceCanister:
         """Call the canister method to add an image hash."""
         return self.governance.add_image_hash(str(subject_id), image_hash)
 
+    def register_image_hash(self, subject_id: uuid.UUID, image_hash: str, algorithm: str = "Sha256",
+                            prediction_id: str | None = None, image_name: str | None = None):
+        """Call the canister method to add an image hash along with its algorithm and origin."""
+        registration = {
+            "image_hash": image_hash,
+            "algorithm": {algorithm: None},
+            "prediction_id": [prediction_id] if prediction_id else [],
+            "image_name": [image_name] if image_name else [],
+        }
+        return self.governance.register_image_hash(str(subject_id), registration)
+
     def get_image_hashes(self, subject_id: uuid.UUID):
         """Call the canister method to get image hashes."""
         return self.governance.get_image_hashes(str(subject_id))
 
+    def find_subjects_by_hash(self, image_hash: str):
+        """Call the canister method to find the subjects that registered an image hash."""
+        return self.governance.find_subjects_by_hash(image_hash)
+
+    def find_subjects_by_hashes(self, image_hashes: list[str]):
+        """Call the canister method to find the subjects of several image hashes at once."""
+        return self.governance.find_subjects_by_hashes(image_hashes)
+
 
 governance_canister_instance = GovernanceCanister()
@@ -1,24 +1,28 @@
-use std::{borrow::Cow, cell::RefCell};
-use ic_cdk::api::{time, management_canister::http_request::{
+use std::{borrow::Cow, cell::RefCell, time::Duration};
+use ic_cdk::api::{call::{msg_cycles_accept128, msg_cycles_available128, msg_cycles_refunded128, RejectionCode}, time, management_canister::http_request::{
     http_request, CanisterHttpRequestArgument, HttpHeader, HttpMethod, HttpResponse, TransformArgs,
     TransformContext,
 }};
-use candid::{CandidType, Decode, Encode, Nat};
+use candid::{CandidType, Decode, Encode, Int, Nat, Principal};
+use ic_certification::{fork, labeled, leaf, HashTree};
 use ic_stable_structures::{
     memory_manager::{MemoryId, MemoryManager, VirtualMemory},
     storable::Bound,
-    DefaultMemoryImpl, StableBTreeMap, Storable,
+    DefaultMemoryImpl, StableBTreeMap, StableCell, StableLog, Storable,
 };
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::collections::HashMap;
 
 
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 
-#[derive(Serialize, Deserialize)]
+/// Passed to `transform` with each outcall to select the response schema
+/// to normalize
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
 struct Context {
-    bucket_start_time_index: usize,
-    closing_price_index: usize,
+    provider: DetectionProvider,
 }
 
 #[derive(CandidType, Serialize, Deserialize, Clone)]
@@ -26,6 +30,146 @@ struct StoredImage {
     content: Vec<u8>,
     prediction_id: String,
     uploaded_by: String,
+    /// Why the most recent crawl of this image failed; cleared by a successful crawl
+    last_error: Option<CrawlErrorRecord>,
+    /// The person depicted, whose consent the image was stored under
+    subject_id: Option<String>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+struct CrawlErrorRecord {
+    message: String,
+    /// Whether the failure was transient and worth retrying
+    transient: bool,
+    occurred_at: u64,
+    /// Crawl job queued to retry a transient failure of `detect_image` or
+    /// `detect_image_aggregated`
+    retry_job_id: Option<u64>,
+}
+
+/// Error returned by every endpoint. Each variant carries a readable message
+/// for display, while clients can match on the kind of failure.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+enum SentinelError {
+    NotFound { message: String },
+    AccessDenied { message: String },
+    /// `field` names the offending argument
+    InvalidArgument { field: String, message: String },
+    AlreadyExists { message: String },
+    /// The current state does not allow the operation, e.g. a transfer offer
+    /// that is no longer pending or a subject without active consent
+    FailedPrecondition { message: String },
+    QuotaExceeded { message: String },
+    InsufficientFunds { message: String },
+    /// `code` is the HTTP status returned by the proxy, or the IC rejection
+    /// code when the outcall got no response
+    OutcallFailed { code: u32, message: String },
+    ParseError { message: String },
+    LedgerError { message: String },
+    Internal { message: String },
+}
+
+impl SentinelError {
+    fn not_found(message: impl Into<String>) -> Self {
+        SentinelError::NotFound { message: message.into() }
+    }
+
+    fn access_denied(message: impl Into<String>) -> Self {
+        SentinelError::AccessDenied { message: message.into() }
+    }
+
+    fn invalid(field: &str, message: impl Into<String>) -> Self {
+        SentinelError::InvalidArgument {
+            field: field.to_string(),
+            message: message.into(),
+        }
+    }
+
+    fn already_exists(message: impl Into<String>) -> Self {
+        SentinelError::AlreadyExists { message: message.into() }
+    }
+
+    fn failed_precondition(message: impl Into<String>) -> Self {
+        SentinelError::FailedPrecondition { message: message.into() }
+    }
+
+    fn quota_exceeded(message: impl Into<String>) -> Self {
+        SentinelError::QuotaExceeded { message: message.into() }
+    }
+
+    fn insufficient_funds(message: impl Into<String>) -> Self {
+        SentinelError::InsufficientFunds { message: message.into() }
+    }
+
+    fn outcall_failed(code: u32, message: impl Into<String>) -> Self {
+        SentinelError::OutcallFailed {
+            code,
+            message: message.into(),
+        }
+    }
+
+    fn parse_error(message: impl Into<String>) -> Self {
+        SentinelError::ParseError { message: message.into() }
+    }
+
+    fn ledger_error(message: impl Into<String>) -> Self {
+        SentinelError::LedgerError { message: message.into() }
+    }
+
+    fn internal(message: impl Into<String>) -> Self {
+        SentinelError::Internal { message: message.into() }
+    }
+
+    fn message(&self) -> &str {
+        match self {
+            SentinelError::NotFound { message }
+            | SentinelError::AccessDenied { message }
+            | SentinelError::InvalidArgument { message, .. }
+            | SentinelError::AlreadyExists { message }
+            | SentinelError::FailedPrecondition { message }
+            | SentinelError::QuotaExceeded { message }
+            | SentinelError::InsufficientFunds { message }
+            | SentinelError::OutcallFailed { message, .. }
+            | SentinelError::ParseError { message }
+            | SentinelError::LedgerError { message }
+            | SentinelError::Internal { message } => message,
+        }
+    }
+}
+
+impl std::fmt::Display for SentinelError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.message())
+    }
+}
+
+/// Why a crawl failed. Transient failures (rejected or timed out outcalls,
+/// 5xx and 429 responses) are retried by the crawl queue.
+enum CrawlError {
+    Transient(SentinelError),
+    Permanent(SentinelError),
+}
+
+impl CrawlError {
+    fn error(&self) -> &SentinelError {
+        match self {
+            CrawlError::Transient(err) | CrawlError::Permanent(err) => err,
+        }
+    }
+}
+
+impl From<SentinelError> for CrawlError {
+    fn from(err: SentinelError) -> Self {
+        CrawlError::Permanent(err)
+    }
+}
+
+impl From<CrawlError> for SentinelError {
+    fn from(err: CrawlError) -> Self {
+        match err {
+            CrawlError::Transient(err) | CrawlError::Permanent(err) => err,
+        }
+    }
 }
 
 fn default_last_update() -> u64 {
@@ -43,12 +187,189 @@ struct CrawlResult {
     visually_similar_images: Vec<String>,
     #[serde(default = "default_last_update")]
     last_update: u64,
+    /// Set when consent for the crawled content had been revoked at crawl
+    /// time, making every match in this result unauthorized
+    #[serde(default)]
+    consent_revoked_at: Option<u64>,
+    /// Detection provider that produced this result
+    #[serde(default)]
+    source: Option<DetectionProvider>,
+    /// ID the provider gave the search, for single-provider crawls
+    #[serde(default)]
+    provider_job_id: Option<String>,
+    /// Providers whose results were merged, for aggregated crawls
+    #[serde(default)]
+    sources: Option<Vec<DetectionProvider>>,
+    /// Every match with its details; the URL lists above are derived from these
+    #[serde(default)]
+    matches: Option<Vec<Match>>,
+}
+
+/// Kinds of match, strongest first
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+enum MatchType {
+    /// A copy of the image itself, possibly resized
+    FullMatch,
+    /// A page showing the image, when the provider does not say which copy
+    PageMatch,
+    VisuallySimilar,
+}
+
+/// An image or page a crawl found
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+struct Match {
+    url: String,
+    /// Page the image was found on
+    #[serde(default)]
+    page_url: Option<String>,
+    #[serde(default)]
+    page_title: Option<String>,
+    #[serde(default)]
+    thumbnail_url: Option<String>,
+    match_type: MatchType,
+    /// The provider's own score for single-provider crawls; for aggregated
+    /// crawls the share of answering providers reporting the match, from 0 to 1
+    #[serde(default)]
+    score: Option<f64>,
+    #[serde(default)]
+    first_seen: u64,
+    #[serde(default)]
+    last_seen: u64,
+    /// Host of `page_url`, or of `url` when the page is unknown
+    #[serde(default)]
+    domain: String,
+    #[serde(default)]
+    sources: Vec<DetectionProvider>,
+}
+
+/// When a matched URL was found online, across all crawls of an image
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+struct SeenUrl {
+    /// Canonical URL, see `canonical_url`
+    url: String,
+    /// Page the URL was last found on
+    page_url: Option<String>,
+    domain: String,
+    /// Strongest type of match the URL was reported as
+    match_type: MatchType,
+    first_seen: u64,
+    last_seen: u64,
+    /// Set when a crawl no longer finds the URL; cleared once it is found again
+    gone_since: Option<u64>,
+    /// Number of crawls that found the URL
+    times_seen: u64,
+    /// Providers that reported the URL
+    sources: Vec<DetectionProvider>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Default)]
+struct MatchHistory {
+    urls: Vec<SeenUrl>,
+}
+
+/// Crawl results with matches on one domain, kept in sync with `STABLE_CRAWL_RESULTS`
+#[derive(CandidType, Serialize, Deserialize, Clone, Default)]
+struct DomainIndexEntry {
+    images: Vec<DomainImage>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+struct DomainImage {
+    /// Key of the crawl result, `owner:image_name`
+    crawl_key: String,
+    matches: u64,
+    /// Subject shown in the crawled image, when it is stored with one
+    subject_id: Option<String>,
+    /// Prediction of the crawl result, whose consent is checked when querying
+    prediction_id: Option<String>,
+}
+
+/// How much of the monitored content a domain carries
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+struct DomainStats {
+    domain: String,
+    images: u64,
+    /// Distinct subjects shown in the matched images
+    models: u64,
+    matches: u64,
+    unauthorized_matches: u64,
+}
+
+/// Reverse image search backend a crawl is run against
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+enum DetectionProvider {
+    /// Shootify's proxy in front of Google Vision web detection
+    #[default]
+    ShootifyProxy,
+    TinEye,
+    Bing,
 }
 
 impl CrawlResult {
     fn set_last_update_to_now(&mut self) {
         self.last_update = time();
     }
+
+    /// Build `matches` from the URL lists for providers that only report
+    /// URLs, and fill in what the canister knows about every match
+    fn complete_matches(&mut self, provider: DetectionProvider) {
+        if self.matches.is_none() {
+            let lists = [
+                (&self.full_matching_images, MatchType::FullMatch),
+                (&self.pages_with_matching_images, MatchType::PageMatch),
+                (&self.visually_similar_images, MatchType::VisuallySimilar),
+            ];
+            let matches = lists
+                .into_iter()
+                .flat_map(|(urls, match_type)| {
+                    urls.iter().map(move |url| Match {
+                        url: url.clone(),
+                        page_url: None,
+                        page_title: None,
+                        thumbnail_url: None,
+                        match_type,
+                        score: None,
+                        first_seen: 0,
+                        last_seen: 0,
+                        domain: String::new(),
+                        sources: Vec::new(),
+                    })
+                })
+                .collect();
+            self.matches = Some(matches);
+        }
+
+        let now = time();
+        for found in self.matches.iter_mut().flatten() {
+            found.first_seen = now;
+            found.last_seen = now;
+            found.domain = url_domain(found.page_url.as_deref().unwrap_or(&found.url));
+            found.sources = vec![provider];
+        }
+        self.derive_url_lists();
+    }
+
+    /// Rebuild the URL lists older clients read from `matches`
+    fn derive_url_lists(&mut self) {
+        let mut full_matching_images = Vec::new();
+        let mut pages_with_matching_images = Vec::new();
+        let mut visually_similar_images = Vec::new();
+        for found in self.matches.iter().flatten() {
+            match found.match_type {
+                MatchType::FullMatch => {
+                    push_unique(&mut full_matching_images, &found.url);
+                    if let Some(page_url) = &found.page_url {
+                        push_unique(&mut pages_with_matching_images, page_url);
+                    }
+                }
+                MatchType::PageMatch => push_unique(&mut pages_with_matching_images, &found.url),
+                MatchType::VisuallySimilar => push_unique(&mut visually_similar_images, &found.url),
+            }
+        }
+        self.full_matching_images = full_matching_images;
+        self.pages_with_matching_images = pages_with_matching_images;
+        self.visually_similar_images = visually_similar_images;
+    }
 }
 
 #[derive(CandidType, Serialize, Deserialize, Clone, Default)]
@@ -56,267 +377,5381 @@ struct StorableVecString {
     images: Vec<String>,
 }
 
-impl Storable for StorableVecString {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+enum HashAlgorithm {
+    Sha256,
+    PHash,
+    DHash,
+    AHash,
+    Other(String),
+    /// Registered without naming an algorithm, including entries that
+    /// predate hash metadata
+    Unknown,
+}
+
+/// A hash registered for a subject and where it came from. Entries migrated
+/// from bare hashes have no `registered_by`/`registered_at`.
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+struct SubjectImageEntry {
+    image_hash: String,
+    algorithm: HashAlgorithm,
+    registered_by: Option<String>,
+    registered_at: Option<u64>,
+    prediction_id: Option<String>,
+    image_name: Option<String>,
+    revoked_at: Option<u64>,
+}
+
+impl SubjectImageEntry {
+    fn registered_now(mut self) -> Self {
+        self.registered_by = Some(ic_cdk::caller().to_text());
+        self.registered_at = Some(time());
+        self
     }
 
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap_or_else(|e| {
-            ic_cdk::trap(&format!("Failed to decode StorableVecString: {}", e));
-        })
+    fn legacy(image_hash: String) -> Self {
+        Self {
+            image_hash,
+            algorithm: HashAlgorithm::Unknown,
+            registered_by: None,
+            registered_at: None,
+            prediction_id: None,
+            image_name: None,
+            revoked_at: None,
+        }
     }
+}
 
-    const BOUND: Bound = Bound::Unbounded;
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+struct HashRegistration {
+    image_hash: String,
+    algorithm: HashAlgorithm,
+    prediction_id: Option<String>,
+    image_name: Option<String>,
+}
+
+impl HashRegistration {
+    fn into_entry(self) -> SubjectImageEntry {
+        SubjectImageEntry {
+            image_hash: self.image_hash,
+            algorithm: self.algorithm,
+            registered_by: None,
+            registered_at: None,
+            prediction_id: self.prediction_id.filter(|id| !id.is_empty()),
+            image_name: self.image_name.filter(|name| !name.is_empty()),
+            revoked_at: None,
+        }
+        .registered_now()
+    }
 }
 
+/// The hashes registered for a subject
+#[derive(CandidType, Serialize, Deserialize, Clone, Default)]
+struct SubjectImages {
+    entries: Vec<SubjectImageEntry>,
+}
 
-impl Storable for CrawlResult {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
+impl SubjectImages {
+    fn contains(&self, image_hash: &str) -> bool {
+        self.entries.iter().any(|entry| entry.image_hash == image_hash)
     }
 
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap_or_else(|e| {
-            ic_cdk::trap(&format!("Failed to decode CrawlResult: {}", e));
-        })
+    fn hashes(&self) -> Vec<String> {
+        self.entries.iter().map(|entry| entry.image_hash.clone()).collect()
     }
+}
 
-    const BOUND: Bound = Bound::Unbounded;
+/// Subjects used to be stored as bare hash lists
+impl From<StorableVecString> for SubjectImages {
+    fn from(legacy: StorableVecString) -> Self {
+        Self {
+            entries: legacy.images.into_iter().map(SubjectImageEntry::legacy).collect(),
+        }
+    }
 }
 
-impl Storable for StoredImage {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+enum UsageScope {
+    Editorial,
+    Ecommerce,
+    Advertising,
+    SocialMedia,
+    Print,
+    Other(String),
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+struct ConsentTerms {
+    scopes: Vec<UsageScope>,
+    /// Brands the content may be used for; empty means any brand
+    brands: Vec<String>,
+    expires_at: Option<u64>,
+}
+
+/// A model's consent to having content registered under their subject ID,
+/// signed by the principal that granted it
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+struct ConsentRecord {
+    subject_id: String,
+    model_principal: String,
+    scopes: Vec<UsageScope>,
+    brands: Vec<String>,
+    signed_at: u64,
+    expires_at: Option<u64>,
+    revoked_at: Option<u64>,
+}
+
+/// What has to be followed up after a consent revocation: the content that
+/// became unauthorized and the licensees that were allowed to use it
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+struct RevocationNotice {
+    subject_id: String,
+    revoked_at: u64,
+    revoked_by: String,
+    image_hashes: Vec<String>,
+    image_names: Vec<String>,
+    prediction_ids: Vec<String>,
+    licensees: Vec<String>,
+}
+
+impl ConsentRecord {
+    fn is_active(&self, now: u64) -> bool {
+        self.revoked_at.is_none() && self.expires_at.is_none_or(|expires_at| expires_at > now)
     }
+}
 
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap_or_else(|e| {
-            ic_cdk::trap(&format!("Failed to decode StoredImage: {}", e));
-        })
+/// Everything registered under a single prediction_id, kept in sync by the
+/// endpoints that write images, crawl results and hashes.
+#[derive(CandidType, Serialize, Deserialize, Clone, Default)]
+struct PredictionIndexEntry {
+    image_names: Vec<String>,
+    crawl_keys: Vec<String>,
+    image_hashes: Vec<String>,
+}
+
+impl PredictionIndexEntry {
+    fn is_empty(&self) -> bool {
+        self.image_names.is_empty() && self.crawl_keys.is_empty() && self.image_hashes.is_empty()
     }
+}
 
-    const BOUND: Bound = Bound::Unbounded;
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+struct PredictionDetails {
+    prediction_id: String,
+    image_name: Option<String>,
+    uploaded_by: Option<String>,
+    content_size: Option<u64>,
+    image_hashes: Vec<String>,
+    latest_crawl_result: Option<CrawlResult>,
 }
 
+/// Canister-wide settings, changed by controllers only. Fields are optional so
+/// that new settings can be added without breaking the stored value.
+#[derive(CandidType, Serialize, Deserialize, Clone, Default)]
+struct Config {
+    trash_retention_secs: Option<u64>,
+    /// Quota for users without a tier or per-user override
+    default_quota: Option<Quota>,
+    tier_quotas: Option<Vec<TierQuota>>,
+    /// Refuse outcalls that the caller's cycle balance does not cover
+    require_prepaid_cycles: Option<bool>,
+    /// Largest crawl response accepted; outcalls are priced by this limit
+    max_response_bytes: Option<u64>,
+    /// Number of nodes in the canister's subnet, which scales outcall prices
+    subnet_size: Option<u64>,
+    /// ICRC-2 ledger crawl credits are paid with; crawls need a credit once set
+    payment_ledger: Option<Principal>,
+    /// Ledger token units charged per crawl credit
+    price_per_credit: Option<u64>,
+    provider_endpoints: Option<Vec<ProviderEndpoint>>,
+}
 
-thread_local! {
-    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
-        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+/// Where and with which key a detection provider is queried
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+struct ProviderEndpoint {
+    provider: DetectionProvider,
+    url: String,
+    api_key: Option<String>,
+}
 
-    static STABLE_IMAGES: RefCell<StableBTreeMap<String, StoredImage, Memory>> = RefCell::new(
-        StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))),
-        )
-    );
+/// Limits on what a single user may store and crawl; `None` means unlimited
+#[derive(CandidType, Serialize, Deserialize, Clone, Default, Debug)]
+struct Quota {
+    max_images: Option<u64>,
+    max_bytes: Option<u64>,
+    max_crawls_per_day: Option<u64>,
+}
 
-    static STABLE_CRAWL_RESULTS: RefCell<StableBTreeMap<String, CrawlResult, Memory>> = RefCell::new(
-        StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1))),
-        )
-    );
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+struct TierQuota {
+    tier: String,
+    quota: Quota,
+}
 
-    static STABLE_SUBJECT_IMAGES: RefCell<StableBTreeMap<String, StorableVecString, Memory>> = RefCell::new(
-        StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2))),
-        )
-    );
+/// Quota settings of a user, their crawl counter for the current day and
+/// the images they store
+#[derive(CandidType, Serialize, Deserialize, Clone, Default)]
+struct UserAccount {
+    tier: Option<String>,
+    /// Overrides the tier quota for this user
+    quota: Option<Quota>,
+    crawl_day: u64,
+    crawls_today: u64,
+    /// Images owned by the user, trashed ones included, kept up to date by
+    /// `track_image_storage`
+    image_count: Option<u64>,
+    total_bytes: Option<u64>,
 }
 
-/// Store a crawl result
-fn store_crawl_result(user_id: String, image_name: String, mut result: CrawlResult) -> Result<(), String> {
-    let key = format!("{}:{}", user_id, image_name);
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+struct Usage {
+    user_id: String,
+    tier: Option<String>,
+    quota: Quota,
+    image_count: u64,
+    total_bytes: u64,
+    crawls_today: u64,
+}
 
-    result.set_last_update_to_now();
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+struct SubjectHash {
+    subject_id: String,
+    image_hash: String,
+    registration: Option<SubjectImageEntry>,
+}
 
-    STABLE_CRAWL_RESULTS.with_borrow_mut(|results| {
-        results.insert(key.clone(), result);
-        ic_cdk::println!("Crawl result stored for key '{}'", key);
-    });
+/// A deleted image and everything that was removed along with it, kept until `purge_at`
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+struct TrashedImage {
+    image: StoredImage,
+    crawl_result: Option<CrawlResult>,
+    match_history: Option<MatchHistory>,
+    subject_hashes: Vec<SubjectHash>,
+    deleted_at: u64,
+    purge_at: u64,
+}
 
-    Ok(())
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+struct TrashedImageSummary {
+    name: String,
+    prediction_id: String,
+    deleted_at: u64,
+    purge_at: u64,
 }
 
-/// Store an image associated with a user ID
-#[ic_cdk::update]
-fn store_image(user_id: String, prediction_id: String, name: String, content: Vec<u8>) -> Result<(), String> {
-    if user_id.is_empty() {
-        return Err("User ID cannot be empty.".to_string());
-    }
-    if name.is_empty() {
-        return Err("Image name cannot be empty.".to_string());
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+enum SubjectAction {
+    HashAdded,
+    HashRemoved,
+    HashesReplaced,
+    SubjectDeleted,
+    ConsentRevoked,
+}
+
+/// One change to a subject's registered hashes and the principal that made it
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+struct SubjectAuditEvent {
+    action: SubjectAction,
+    image_hashes: Vec<String>,
+    caller: String,
+    timestamp: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Default)]
+struct SubjectAuditTrail {
+    events: Vec<SubjectAuditEvent>,
+}
+
+impl Storable for SubjectAuditTrail {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
     }
-    if content.is_empty() {
-        return Err("Image content cannot be empty.".to_string());
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap_or_else(|e| {
+            ic_cdk::trap(&format!("Failed to decode SubjectAuditTrail: {}", e));
+        })
     }
 
-    STABLE_IMAGES.with_borrow_mut(|images| {
-        if images.contains_key(&name) {
-            Err(format!("An image with the name '{}' already exists.", name))
-        } else {
-            images.insert(
-                name.clone(),
-                StoredImage {
-                    content,
-                    prediction_id: prediction_id.clone(),
-                    uploaded_by: user_id.clone(),
-                },
-            );
-            ic_cdk::println!("Image '{}' stored successfully by user '{}'", name, user_id);
-            Ok(())
-        }
-    })
+    const BOUND: Bound = Bound::Unbounded;
 }
 
-#[ic_cdk::update]
-fn add_image_hash(subject_id: String, image_hash: String) -> Result<(), String> {
-    if subject_id.is_empty() {
-        return Err("Subject ID cannot be empty.".to_string());
-    }
-    if image_hash.is_empty() {
-        return Err("Image hash cannot be empty.".to_string());
+impl Storable for Config {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
     }
 
-    STABLE_SUBJECT_IMAGES.with_borrow_mut(|subject_images| {
-        let mut entry = subject_images.get(&subject_id).unwrap_or_default();
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap_or_else(|e| {
+            ic_cdk::trap(&format!("Failed to decode Config: {}", e));
+        })
+    }
 
-        // Check if the image hash already exists
-        if !entry.images.contains(&image_hash) {
-            entry.images.push(image_hash.clone());
-            subject_images.insert(subject_id.clone(), entry);
-            ic_cdk::println!("Added image_hash '{}' to subject_id '{}'", image_hash, subject_id);
-        } else {
-            ic_cdk::println!("Image_hash '{}' already exists for subject_id '{}'", image_hash, subject_id);
-        }
+    const BOUND: Bound = Bound::Unbounded;
+}
 
-        Ok(())
-    })
+#[derive(CandidType, Serialize, Deserialize, Clone, Default)]
+struct CyclesAccount {
+    balance: u64,
+    total_spent: u64,
+    /// Cycles spent beyond the balance while prepaid cycles were not
+    /// required; settled by the next deposits and grants
+    overdraft: Option<u64>,
 }
 
-#[ic_cdk::query]
-fn get_image_hashes(subject_id: String) -> Result<Vec<String>, String> {
-    if subject_id.is_empty() {
-        return Err("Subject ID cannot be empty.".to_string());
+impl CyclesAccount {
+    fn debit(&mut self, cycles: u64) {
+        let shortfall = cycles.saturating_sub(self.balance);
+        self.balance -= cycles - shortfall;
+        self.overdraft = Some(self.overdraft.unwrap_or(0) + shortfall).filter(|overdraft| *overdraft > 0);
     }
 
-    STABLE_SUBJECT_IMAGES.with_borrow(|subject_images| {
-        if let Some(entry) = subject_images.get(&subject_id) {
-            Ok(entry.images.clone())
-        } else {
-            Err("Subject ID not found.".to_string())
-        }
-    })
+    fn credit(&mut self, cycles: u64) {
+        let overdraft = self.overdraft.unwrap_or(0);
+        let settled = cycles.min(overdraft);
+        self.overdraft = Some(overdraft - settled).filter(|overdraft| *overdraft > 0);
+        self.balance += cycles - settled;
+    }
 }
 
-/// List all crawl results for a specific user ID
-#[ic_cdk::query]
-fn get_crawl_results(user_id: String) -> Result<String, String> {
-    ic_cdk::println!("User ID received: '{}'", user_id);
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+enum CyclesEntryKind {
+    /// Cycles attached to a `deposit_cycles` call
+    Deposit,
+    /// Cycles credited by a controller
+    Grant,
+    /// Cycles attached to an HTTP outcall crawling `image_name`, reserved
+    /// before the call is made
+    Outcall { image_name: String },
+    /// Part of an outcall's reservation that was not used
+    OutcallRefund { image_name: String },
+}
 
-    if user_id.is_empty() {
-        return Err("User ID is empty.".to_string());
-    }
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+struct CyclesLedgerEntry {
+    id: u64,
+    user_id: String,
+    kind: CyclesEntryKind,
+    /// Cycles credited, or for outcalls the cycles actually used
+    cycles: u64,
+    /// Cycles attached to an outcall; the difference to `cycles` was refunded
+    cycles_attached: Option<u64>,
+    balance_after: u64,
+    timestamp: u64,
+}
 
-    let prefix = format!("{}:", user_id);
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+struct ImageCycles {
+    image_name: String,
+    outcalls: u64,
+    cycles_attached: u64,
+    cycles_refunded: u64,
+    cycles_spent: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+struct Account {
+    owner: Principal,
+    subaccount: Option<Vec<u8>>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+struct TransferFromArgs {
+    spender_subaccount: Option<Vec<u8>>,
+    from: Account,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+enum TransferFromError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    InsufficientAllowance { allowance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+/// Crawl credits bought through an ICRC-2 `transfer_from`
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+struct Payment {
+    id: u64,
+    user_id: String,
+    payer: Principal,
+    ledger: Principal,
+    amount: u64,
+    credits: u64,
+    block_index: Nat,
+    timestamp: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+enum CrawlJobStatus {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+}
+
+/// A crawl of a stored image, run in the background by `process_crawl_queue`
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+struct CrawlJob {
+    id: u64,
+    user_id: String,
+    prediction_id: String,
+    image_name: String,
+    status: CrawlJobStatus,
+    /// Crawl result as JSON, in the same format `detect_image` returns
+    result: Option<String>,
+    error: Option<String>,
+    enqueued_at: u64,
+    started_at: Option<u64>,
+    finished_at: Option<u64>,
+    /// Outcalls made so far, including failed ones
+    attempts: Option<u32>,
+    /// Earliest time a job waiting for a retry may run again
+    retry_at: Option<u64>,
+    /// Provider to crawl with; the Shootify proxy when `None`
+    provider: Option<DetectionProvider>,
+    /// Providers of an aggregated crawl, used instead of `provider` when set
+    providers: Option<Vec<DetectionProvider>>,
+}
+
+impl Storable for CrawlJob {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap_or_else(|e| {
+            ic_cdk::trap(&format!("Failed to decode CrawlJob: {}", e));
+        })
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for Payment {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap_or_else(|e| {
+            ic_cdk::trap(&format!("Failed to decode Payment: {}", e));
+        })
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for CyclesAccount {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap_or_else(|e| {
+            ic_cdk::trap(&format!("Failed to decode CyclesAccount: {}", e));
+        })
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for CyclesLedgerEntry {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap_or_else(|e| {
+            ic_cdk::trap(&format!("Failed to decode CyclesLedgerEntry: {}", e));
+        })
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for UserAccount {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap_or_else(|e| {
+            ic_cdk::trap(&format!("Failed to decode UserAccount: {}", e));
+        })
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// The subjects that registered a given hash
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+struct HashSubjects {
+    image_hash: String,
+    subject_ids: Vec<String>,
+    /// Subjects among `subject_ids` whose consent for this hash was revoked
+    revoked_subject_ids: Vec<String>,
+}
+
+impl Storable for DomainIndexEntry {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap_or_else(|e| {
+            ic_cdk::trap(&format!("Failed to decode DomainIndexEntry: {}", e));
+        })
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for MatchHistory {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap_or_else(|e| {
+            ic_cdk::trap(&format!("Failed to decode MatchHistory: {}", e));
+        })
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for TrashedImage {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap_or_else(|e| {
+            ic_cdk::trap(&format!("Failed to decode TrashedImage: {}", e));
+        })
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for SubjectImages {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self)
+            .or_else(|_| Decode!(bytes.as_ref(), StorableVecString).map(Self::from))
+            .unwrap_or_else(|e| {
+                ic_cdk::trap(&format!("Failed to decode SubjectImages: {}", e));
+            })
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+enum AuditOutcome {
+    Ok,
+    Err(String),
+}
+
+/// One update call as recorded in the audit log
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+struct AuditEntry {
+    timestamp: u64,
+    caller: String,
+    method: String,
+    user_id: Option<String>,
+    arguments: String,
+    outcome: AuditOutcome,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+struct AuditLogQuery {
+    /// Matches either the user/subject ID a call was made for or the calling principal
+    user: Option<String>,
+    from: Option<u64>,
+    to: Option<u64>,
+    /// Log index to resume from, as returned in `AuditLogPage::next`
+    start: Option<u64>,
+    limit: Option<u64>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+struct IndexedAuditEntry {
+    index: u64,
+    entry: AuditEntry,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+struct AuditLogPage {
+    entries: Vec<IndexedAuditEntry>,
+    next: Option<u64>,
+}
+
+/// ICRC-3 generic value; every block of the registration ledger is a `Map`
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+enum Icrc3Value {
+    Blob(Vec<u8>),
+    Text(String),
+    Nat(Nat),
+    Int(Int),
+    Array(Vec<Icrc3Value>),
+    Map(Vec<(String, Icrc3Value)>),
+}
+
+impl Icrc3Value {
+    /// Representation-independent hash as defined by ICRC-3
+    fn hash(&self) -> [u8; 32] {
+        match self {
+            Icrc3Value::Blob(bytes) => Sha256::digest(bytes).into(),
+            Icrc3Value::Text(text) => Sha256::digest(text.as_bytes()).into(),
+            Icrc3Value::Nat(nat) => {
+                let mut buf = Vec::new();
+                nat.encode(&mut buf).expect("LEB128 encoding into a Vec cannot fail");
+                Sha256::digest(&buf).into()
+            }
+            Icrc3Value::Int(int) => {
+                let mut buf = Vec::new();
+                int.encode(&mut buf).expect("SLEB128 encoding into a Vec cannot fail");
+                Sha256::digest(&buf).into()
+            }
+            Icrc3Value::Array(values) => {
+                let mut hasher = Sha256::new();
+                for value in values {
+                    hasher.update(value.hash());
+                }
+                hasher.finalize().into()
+            }
+            Icrc3Value::Map(entries) => {
+                let mut pairs: Vec<Vec<u8>> = entries
+                    .iter()
+                    .map(|(key, value)| {
+                        let mut pair = Sha256::digest(key.as_bytes()).to_vec();
+                        pair.extend_from_slice(&value.hash());
+                        pair
+                    })
+                    .collect();
+                pairs.sort();
+
+                let mut hasher = Sha256::new();
+                for pair in pairs {
+                    hasher.update(pair);
+                }
+                hasher.finalize().into()
+            }
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+struct GetBlocksArgs {
+    start: Nat,
+    length: Nat,
+}
+
+#[derive(CandidType)]
+struct BlockWithId {
+    id: Nat,
+    block: Icrc3Value,
+}
+
+candid::define_function!(GetBlocksCallback : (Vec<GetBlocksArgs>) -> (GetBlocksResult) query);
+
+#[derive(CandidType)]
+struct ArchivedBlocks {
+    args: Vec<GetBlocksArgs>,
+    callback: GetBlocksCallback,
+}
+
+#[derive(CandidType)]
+struct GetBlocksResult {
+    log_length: Nat,
+    blocks: Vec<BlockWithId>,
+    archived_blocks: Vec<ArchivedBlocks>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct GetArchivesArgs {
+    from: Option<candid::Principal>,
+}
+
+#[derive(CandidType)]
+struct ArchiveInfo {
+    canister_id: candid::Principal,
+    start: Nat,
+    end: Nat,
+}
+
+#[derive(CandidType)]
+struct Icrc3DataCertificate {
+    certificate: Vec<u8>,
+    hash_tree: Vec<u8>,
+}
+
+#[derive(CandidType)]
+struct SupportedBlockType {
+    block_type: String,
+    url: String,
+}
+
+impl Storable for Icrc3Value {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap_or_else(|e| {
+            ic_cdk::trap(&format!("Failed to decode Icrc3Value: {}", e));
+        })
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// The content an ownership transfer or access grant applies to
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+enum ContentScope {
+    Image(String),
+    /// Every image of the user showing the subject, including images added later
+    Subject(String),
+    /// Replaced by `Subject`; only kept so that older grants and offers still
+    /// decode. They were revoked or cancelled when upgrading to schema version 5.
+    Portfolio,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+enum TransferStatus {
+    Pending,
+    Accepted,
+    Cancelled,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+struct TransferOffer {
+    id: u64,
+    from_user_id: String,
+    to_user_id: String,
+    scope: ContentScope,
+    offered_by: String,
+    created_at: u64,
+    status: TransferStatus,
+    resolved_at: Option<u64>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+enum Permission {
+    Read,
+    Crawl,
+    ManageCases,
+}
+
+/// Access to another user's content, e.g. for an agency or legal counsel
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+struct AccessGrant {
+    id: u64,
+    owner_user_id: String,
+    grantee: String,
+    scope: ContentScope,
+    permissions: Vec<Permission>,
+    granted_by: String,
+    created_at: u64,
+    expires_at: Option<u64>,
+    revoked_at: Option<u64>,
+}
+
+impl AccessGrant {
+    fn is_active(&self, now: u64) -> bool {
+        self.revoked_at.is_none() && self.expires_at.is_none_or(|expires_at| expires_at > now)
+    }
+
+    fn covers(&self, owner_user_id: &str, image_name: &str) -> bool {
+        self.owner_user_id == owner_user_id
+            && match &self.scope {
+                ContentScope::Image(name) => name == image_name,
+                ContentScope::Subject(subject_id) => {
+                    image_subject(image_name).is_some_and(|subject| &subject == subject_id)
+                }
+                ContentScope::Portfolio => false,
+            }
+    }
+}
+
+fn image_subject(image_name: &str) -> Option<String> {
+    STABLE_IMAGES.with_borrow(|images| images.get(&image_name.to_string()))?.subject_id
+}
+
+/// Names of the stored images of `owner_user_id` showing `subject_id`
+fn subject_image_names(owner_user_id: &str, subject_id: &str) -> Vec<String> {
+    STABLE_IMAGES.with_borrow(|images| {
+        images
+            .iter()
+            .filter(|(_, image)| {
+                image.uploaded_by == owner_user_id
+                    && image.subject_id.as_deref() == Some(subject_id)
+            })
+            .map(|(name, _)| name)
+            .collect()
+    })
+}
+
+/// Subject scopes cannot be transferred while some of the subject's images are
+/// in the trash, as restoring them would give them back to the previous owner
+fn ensure_no_trashed_subject_images(
+    owner_user_id: &str,
+    subject_id: &str,
+) -> Result<(), SentinelError> {
+    let trashed = STABLE_TRASH.with_borrow(|trash| {
+        trash.values().any(|trashed| {
+            trashed.image.uploaded_by == owner_user_id
+                && trashed.image.subject_id.as_deref() == Some(subject_id)
+        })
+    });
+    if trashed {
+        return Err(SentinelError::failed_precondition(format!(
+            "Images of subject '{}' are in the trash. Restore or purge them first.",
+            subject_id
+        )));
+    }
+    Ok(())
+}
+
+fn ensure_supported_scope(scope: &ContentScope) -> Result<(), SentinelError> {
+    match scope {
+        ContentScope::Portfolio => Err(SentinelError::invalid(
+            "scope",
+            "The portfolio scope is no longer supported. Use a subject scope instead.",
+        )),
+        ContentScope::Subject(subject_id) if subject_id.is_empty() => {
+            Err(SentinelError::invalid("scope", "Subject ID cannot be empty."))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+enum OrgRole {
+    Admin,
+    Member,
+}
+
+/// An agency managing several models. Admins can act on the content of every
+/// model in the organization, members can read it.
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+struct Organization {
+    org_id: String,
+    name: String,
+    admins: Vec<String>,
+    members: Vec<String>,
+    models: Vec<String>,
+    invited_models: Vec<String>,
+    /// Crawls allowed per budget period across all models; `None` is unlimited
+    crawl_budget: Option<u64>,
+    crawls_used: u64,
+    budget_period_start: u64,
+    created_by: String,
+    created_at: u64,
+}
+
+impl OrgRole {
+    fn allows(self, permission: Permission) -> bool {
+        match self {
+            OrgRole::Admin => true,
+            OrgRole::Member => permission == Permission::Read,
+        }
+    }
+}
+
+impl Organization {
+    fn role_of(&self, user_id: &str) -> Option<OrgRole> {
+        if self.admins.iter().any(|admin| admin == user_id) {
+            Some(OrgRole::Admin)
+        } else if self.members.iter().any(|member| member == user_id) {
+            Some(OrgRole::Member)
+        } else {
+            None
+        }
+    }
+
+    /// Start a new budget period once the current one has elapsed
+    fn roll_budget_period(&mut self, now: u64) {
+        if now >= self.budget_period_start + ORG_BUDGET_PERIOD_SECS * NANOS_PER_SEC {
+            self.budget_period_start = now;
+            self.crawls_used = 0;
+        }
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+struct ModelImages {
+    model_user_id: String,
+    image_names: Vec<String>,
+}
+
+impl Storable for Organization {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap_or_else(|e| {
+            ic_cdk::trap(&format!("Failed to decode Organization: {}", e));
+        })
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for AccessGrant {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap_or_else(|e| {
+            ic_cdk::trap(&format!("Failed to decode AccessGrant: {}", e));
+        })
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for TransferOffer {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap_or_else(|e| {
+            ic_cdk::trap(&format!("Failed to decode TransferOffer: {}", e));
+        })
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for AuditEntry {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap_or_else(|e| {
+            ic_cdk::trap(&format!("Failed to decode AuditEntry: {}", e));
+        })
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for RevocationNotice {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap_or_else(|e| {
+            ic_cdk::trap(&format!("Failed to decode RevocationNotice: {}", e));
+        })
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for ConsentRecord {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap_or_else(|e| {
+            ic_cdk::trap(&format!("Failed to decode ConsentRecord: {}", e));
+        })
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for StorableVecString {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap_or_else(|e| {
+            ic_cdk::trap(&format!("Failed to decode StorableVecString: {}", e));
+        })
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+
+impl Storable for CrawlResult {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap_or_else(|e| {
+            ic_cdk::trap(&format!("Failed to decode CrawlResult: {}", e));
+        })
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for PredictionIndexEntry {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap_or_else(|e| {
+            ic_cdk::trap(&format!("Failed to decode PredictionIndexEntry: {}", e));
+        })
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for StoredImage {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap_or_else(|e| {
+            ic_cdk::trap(&format!("Failed to decode StoredImage: {}", e));
+        })
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+
+    static STABLE_IMAGES: RefCell<StableBTreeMap<String, StoredImage, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))),
+        )
+    );
+
+    static STABLE_CRAWL_RESULTS: RefCell<StableBTreeMap<String, CrawlResult, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1))),
+        )
+    );
+
+    static STABLE_SUBJECT_IMAGES: RefCell<StableBTreeMap<String, SubjectImages, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2))),
+        )
+    );
+
+    static STABLE_PREDICTION_INDEX: RefCell<StableBTreeMap<String, PredictionIndexEntry, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3))),
+        )
+    );
+
+    static STABLE_CONFIG: RefCell<StableCell<Config, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4))),
+            Config::default(),
+        ).expect("Failed to initialize config cell")
+    );
+
+    static STABLE_TRASH: RefCell<StableBTreeMap<String, TrashedImage, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5))),
+        )
+    );
+
+    static STABLE_SUBJECT_AUDIT: RefCell<StableBTreeMap<String, SubjectAuditTrail, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6))),
+        )
+    );
+
+    // Reverse of STABLE_SUBJECT_IMAGES: image hash -> subject ids
+    static STABLE_HASH_SUBJECTS: RefCell<StableBTreeMap<String, StorableVecString, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(7))),
+        )
+    );
+
+    static STABLE_CONSENTS: RefCell<StableBTreeMap<String, ConsentRecord, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8))),
+        )
+    );
+
+    static STABLE_REVOCATION_NOTICES: RefCell<StableBTreeMap<String, RevocationNotice, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9))),
+        )
+    );
+
+    static STABLE_AUDIT_LOG: RefCell<StableLog<AuditEntry, Memory, Memory>> = RefCell::new(
+        StableLog::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10))),
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11))),
+        ).expect("Failed to initialize audit log")
+    );
+
+    static STABLE_BLOCKS: RefCell<StableLog<Icrc3Value, Memory, Memory>> = RefCell::new(
+        StableLog::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12))),
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(13))),
+        ).expect("Failed to initialize block log")
+    );
+
+    static STABLE_TRANSFER_OFFERS: RefCell<StableBTreeMap<u64, TransferOffer, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14))),
+        )
+    );
+
+    static STABLE_ACCESS_GRANTS: RefCell<StableBTreeMap<u64, AccessGrant, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15))),
+        )
+    );
+
+    static STABLE_ORGANIZATIONS: RefCell<StableBTreeMap<String, Organization, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(16))),
+        )
+    );
+
+    static STABLE_USER_ACCOUNTS: RefCell<StableBTreeMap<String, UserAccount, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(17))),
+        )
+    );
+
+    static STABLE_CYCLES_ACCOUNTS: RefCell<StableBTreeMap<String, CyclesAccount, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(18))),
+        )
+    );
+
+    static STABLE_CYCLES_LEDGER: RefCell<StableBTreeMap<u64, CyclesLedgerEntry, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(19))),
+        )
+    );
+
+    static STABLE_CRAWL_CREDITS: RefCell<StableBTreeMap<String, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(20))),
+        )
+    );
+
+    static STABLE_PAYMENTS: RefCell<StableBTreeMap<u64, Payment, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(21))),
+        )
+    );
+
+    static STABLE_CRAWL_JOBS: RefCell<StableBTreeMap<u64, CrawlJob, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(22))),
+        )
+    );
+
+    /// Seen history of matched URLs, keyed like `STABLE_CRAWL_RESULTS`
+    static STABLE_MATCH_HISTORY: RefCell<StableBTreeMap<String, MatchHistory, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(23))),
+        )
+    );
+
+    /// Domain -> crawl results matching it, kept in sync with `STABLE_CRAWL_RESULTS`
+    static STABLE_DOMAIN_INDEX: RefCell<StableBTreeMap<String, DomainIndexEntry, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(24))),
+        )
+    );
+
+    /// Layout version of the stored data, see `migrate_stable_data`
+    static STABLE_SCHEMA_VERSION: RefCell<StableCell<u64, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(25))),
+            0,
+        ).expect("Failed to initialize schema version cell")
+    );
+
+    /// Ids of the queued and running crawl jobs, so the queue never scans finished ones
+    static STABLE_ACTIVE_CRAWL_JOBS: RefCell<StableBTreeMap<u64, (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(27))),
+        )
+    );
+
+    /// User or subject ID -> the principal allowed to act for it, see `link_principal`
+    static STABLE_PRINCIPALS: RefCell<StableBTreeMap<String, Principal, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(26))),
+        )
+    );
+}
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * NANOS_PER_SEC;
+
+/// Length of an organization's crawl budget period
+const ORG_BUDGET_PERIOD_SECS: u64 = 30 * 24 * 60 * 60;
+
+/// Block types written to the ICRC-3 registration ledger
+const BTYPE_REGISTER_IMAGE: &str = "sentinel_register_image";
+const BTYPE_UNREGISTER_IMAGE: &str = "sentinel_unregister_image";
+const BTYPE_REGISTER_HASH: &str = "sentinel_register_hash";
+const BTYPE_UNREGISTER_HASH: &str = "sentinel_unregister_hash";
+const BTYPE_REVOKE_CONSENT: &str = "sentinel_revoke_consent";
+const BTYPE_TRANSFER_IMAGE: &str = "sentinel_transfer_image";
+
+const BLOCK_TYPES_URL: &str = "https://github.com/shootify/shootify-content-sentinel#registration-ledger-icrc-3";
+
+/// Maximum number of blocks returned by a single `icrc3_get_blocks` call
+const MAX_BLOCKS_PER_REQUEST: u64 = 100;
+
+fn text(value: &str) -> Icrc3Value {
+    Icrc3Value::Text(value.to_string())
+}
+
+fn tip_hash_tree(last_block_index: u64, last_block_hash: [u8; 32]) -> HashTree {
+    let mut index = Vec::new();
+    Nat::from(last_block_index)
+        .encode(&mut index)
+        .expect("LEB128 encoding into a Vec cannot fail");
+
+    fork(
+        labeled("last_block_hash", leaf(last_block_hash.to_vec())),
+        labeled("last_block_index", leaf(index)),
+    )
+}
+
+fn tip() -> Option<(u64, [u8; 32])> {
+    STABLE_BLOCKS.with_borrow(|blocks| {
+        let last_index = blocks.len().checked_sub(1)?;
+        let block = blocks.get(last_index)?;
+        Some((last_index, block.hash()))
+    })
+}
+
+fn certify_tip() {
+    if let Some((index, hash)) = tip() {
+        ic_cdk::api::set_certified_data(&tip_hash_tree(index, hash).digest());
+    }
+}
+
+/// Append a block to the registration ledger, chaining it to the previous
+/// block and certifying the new tip
+fn append_block(btype: &str, tx: Vec<(String, Icrc3Value)>) {
+    let mut block = Vec::new();
+    if let Some((_, parent_hash)) = tip() {
+        block.push(("phash".to_string(), Icrc3Value::Blob(parent_hash.to_vec())));
+    }
+    block.push(("btype".to_string(), text(btype)));
+    block.push(("ts".to_string(), Icrc3Value::Nat(Nat::from(time()))));
+    block.push(("tx".to_string(), Icrc3Value::Map(tx)));
+
+    STABLE_BLOCKS.with_borrow(|blocks| {
+        if let Err(e) = blocks.append(&Icrc3Value::Map(block)) {
+            ic_cdk::trap(&format!("Failed to append '{}' block: {:?}", btype, e));
+        }
+    });
+    certify_tip();
+}
+
+fn append_image_block(btype: &str, name: &str, image: &StoredImage) {
+    append_block(
+        btype,
+        vec![
+            ("name".to_string(), text(name)),
+            ("owner".to_string(), text(&image.uploaded_by)),
+            ("prediction_id".to_string(), text(&image.prediction_id)),
+            ("content_hash".to_string(), Icrc3Value::Blob(Sha256::digest(&image.content).to_vec())),
+            ("caller".to_string(), text(&ic_cdk::caller().to_text())),
+        ],
+    );
+}
+
+fn append_hash_block(btype: &str, subject_id: &str, entry: &SubjectImageEntry) {
+    let mut tx = vec![
+        ("subject_id".to_string(), text(subject_id)),
+        ("image_hash".to_string(), text(&entry.image_hash)),
+        ("algorithm".to_string(), text(&format!("{:?}", entry.algorithm))),
+        ("caller".to_string(), text(&ic_cdk::caller().to_text())),
+    ];
+    if let Some(prediction_id) = &entry.prediction_id {
+        tx.push(("prediction_id".to_string(), text(prediction_id)));
+    }
+    append_block(btype, tx);
+}
+
+/// Default and maximum page size of `get_audit_log`
+const DEFAULT_AUDIT_PAGE_SIZE: u64 = 50;
+const MAX_AUDIT_PAGE_SIZE: u64 = 500;
+
+/// An update call in progress; appended to the audit log once its outcome is known
+struct AuditCall {
+    caller: String,
+    method: &'static str,
+    user_id: Option<String>,
+    arguments: String,
+}
+
+impl AuditCall {
+    fn begin(method: &'static str, user_id: Option<&str>, arguments: String) -> Self {
+        Self {
+            caller: ic_cdk::caller().to_text(),
+            method,
+            user_id: user_id.map(str::to_string),
+            arguments,
+        }
+    }
+
+    fn run<T>(self, call: impl FnOnce() -> Result<T, SentinelError>) -> Result<T, SentinelError> {
+        self.finish(call())
+    }
+
+    fn finish<T>(self, result: Result<T, SentinelError>) -> Result<T, SentinelError> {
+        // Stamped on completion so the log stays ordered by time even when
+        // async calls finish out of order
+        let entry = AuditEntry {
+            timestamp: time(),
+            caller: self.caller,
+            method: self.method.to_string(),
+            user_id: self.user_id,
+            arguments: self.arguments,
+            outcome: match &result {
+                Ok(_) => AuditOutcome::Ok,
+                Err(e) => AuditOutcome::Err(e.to_string()),
+            },
+        };
+
+        STABLE_AUDIT_LOG.with_borrow(|log| {
+            if let Err(e) = log.append(&entry) {
+                ic_cdk::println!("Failed to append audit entry for '{}': {:?}", entry.method, e);
+            }
+        });
+        result
+    }
+}
+
+/// How often the trash is checked for images past their restore window
+const TRASH_PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// How often the crawl queue is checked for jobs that were not picked up
+const CRAWL_QUEUE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Crawl jobs with an outcall in flight at the same time
+const MAX_CONCURRENT_CRAWLS: usize = 4;
+
+/// Outcalls a crawl job makes before a transient failure becomes permanent
+const MAX_CRAWL_ATTEMPTS: u32 = 5;
+
+const CRAWL_RETRY_BASE_DELAY: Duration = Duration::from_secs(10);
+
+const CRAWL_RETRY_MAX_DELAY: Duration = Duration::from_secs(30 * 60);
+
+/// How long finished crawl jobs stay available to `get_crawl_job` before they are pruned
+const CRAWL_JOB_RETENTION: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+const CRAWL_JOB_PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+fn config() -> Config {
+    STABLE_CONFIG.with_borrow(|config| config.get().clone())
+}
+
+fn update_config(update: impl FnOnce(&mut Config)) -> Result<(), SentinelError> {
+    ensure_controller()?;
+
+    STABLE_CONFIG.with_borrow_mut(|cell| {
+        let mut config = cell.get().clone();
+        update(&mut config);
+        cell.set(config)
+            .map(|_| ())
+            .map_err(|e| SentinelError::internal(format!("Failed to store config: {:?}", e)))
+    })
+}
+
+fn user_account(user_id: &str) -> UserAccount {
+    STABLE_USER_ACCOUNTS
+        .with_borrow(|accounts| accounts.get(&user_id.to_string()))
+        .unwrap_or_default()
+}
+
+fn update_user_account(user_id: &str, update: impl FnOnce(&mut UserAccount)) {
+    let mut account = user_account(user_id);
+    update(&mut account);
+    STABLE_USER_ACCOUNTS.with_borrow_mut(|accounts| accounts.insert(user_id.to_string(), account));
+}
+
+/// The user's own quota, else the quota of their tier, else the default quota
+fn effective_quota(account: &UserAccount) -> Quota {
+    let config = config();
+    account
+        .quota
+        .clone()
+        .or_else(|| {
+            let tier = account.tier.as_ref()?;
+            config
+                .tier_quotas
+                .unwrap_or_default()
+                .into_iter()
+                .find(|tier_quota| &tier_quota.tier == tier)
+                .map(|tier_quota| tier_quota.quota)
+        })
+        .or(config.default_quota)
+        .unwrap_or_default()
+}
+
+/// Current consumption of a user. Trashed images still occupy stable memory
+/// and count until they are purged.
+fn usage(user_id: &str) -> Usage {
+    let account = user_account(user_id);
+    Usage {
+        user_id: user_id.to_string(),
+        quota: effective_quota(&account),
+        crawls_today: if account.crawl_day == time() / NANOS_PER_DAY { account.crawls_today } else { 0 },
+        tier: account.tier,
+        image_count: account.image_count.unwrap_or(0),
+        total_bytes: account.total_bytes.unwrap_or(0),
+    }
+}
+
+/// Count an image towards the storage usage of its owner, or off it again
+/// once it is purged or handed to another owner
+fn track_image_storage(image: &StoredImage, stored: bool) {
+    let bytes = image.content.len() as u64;
+    update_user_account(&image.uploaded_by, |account| {
+        let image_count = account.image_count.unwrap_or(0);
+        let total_bytes = account.total_bytes.unwrap_or(0);
+        if stored {
+            account.image_count = Some(image_count + 1);
+            account.total_bytes = Some(total_bytes + bytes);
+        } else {
+            account.image_count = Some(image_count.saturating_sub(1));
+            account.total_bytes = Some(total_bytes.saturating_sub(bytes));
+        }
+    });
+}
+
+/// Recount the stored images of every user; the counters predate schema version 2
+fn rebuild_storage_usage() {
+    let users: Vec<String> = STABLE_USER_ACCOUNTS.with_borrow(|accounts| accounts.keys().collect());
+    for user_id in users {
+        update_user_account(&user_id, |account| {
+            account.image_count = None;
+            account.total_bytes = None;
+        });
+    }
+    let images: Vec<StoredImage> = STABLE_IMAGES.with_borrow(|images| images.values().collect());
+    let trashed: Vec<StoredImage> =
+        STABLE_TRASH.with_borrow(|trash| trash.values().map(|trashed| trashed.image).collect());
+    for image in images.iter().chain(&trashed) {
+        track_image_storage(image, true);
+    }
+}
+
+/// Fail if adding `images` images of `bytes` bytes in total would exceed the user's quota
+fn ensure_storage_quota(user_id: &str, images: u64, bytes: u64) -> Result<(), SentinelError> {
+    let account = user_account(user_id);
+    let quota = effective_quota(&account);
+
+    if let Some(max_images) = quota.max_images {
+        if account.image_count.unwrap_or(0) + images > max_images {
+            return Err(SentinelError::quota_exceeded(format!(
+                "Quota exceeded: You can store at most {} images.",
+                max_images
+            )));
+        }
+    }
+    if let Some(max_bytes) = quota.max_bytes {
+        if account.total_bytes.unwrap_or(0) + bytes > max_bytes {
+            return Err(SentinelError::quota_exceeded(format!(
+                "Quota exceeded: You can store at most {} bytes.",
+                max_bytes
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Run `crawl` with `crawls` crawls counted against the user's daily quota.
+/// Like `with_crawl_credit`, the crawls are counted before the outcalls so
+/// that concurrent crawls cannot all pass the check, and given back for the
+/// providers that failed.
+async fn with_crawl_quota<E: From<SentinelError>>(
+    user_id: &str,
+    crawls: u64,
+    crawl: impl std::future::Future<Output = Result<Detection, E>>,
+) -> Result<Detection, E> {
+    let usage = usage(user_id);
+    if let Some(max_crawls) = usage.quota.max_crawls_per_day {
+        if usage.crawls_today + crawls > max_crawls {
+            let err = SentinelError::quota_exceeded(format!(
+                "Quota exceeded: You can run at most {} crawls per day.",
+                max_crawls
+            ));
+            return Err(err.into());
+        }
+    }
+    record_user_crawl(user_id, crawls);
+
+    let result = crawl.await;
+    let failed = result.as_ref().map_or(crawls, |detection| detection.failed_providers);
+    if failed > 0 {
+        release_user_crawls(user_id, failed);
+    }
+    result
+}
+
+fn record_user_crawl(user_id: &str, crawls: u64) {
+    let today = time() / NANOS_PER_DAY;
+    update_user_account(user_id, |account| {
+        if account.crawl_day != today {
+            account.crawl_day = today;
+            account.crawls_today = 0;
+        }
+        account.crawls_today += crawls;
+    });
+}
+
+/// Give back crawls counted by `record_user_crawl`, unless the day has changed since
+fn release_user_crawls(user_id: &str, crawls: u64) {
+    let today = time() / NANOS_PER_DAY;
+    update_user_account(user_id, |account| {
+        if account.crawl_day == today {
+            account.crawls_today = account.crawls_today.saturating_sub(crawls);
+        }
+    });
+}
+
+fn cycles_account(user_id: &str) -> CyclesAccount {
+    STABLE_CYCLES_ACCOUNTS
+        .with_borrow(|accounts| accounts.get(&user_id.to_string()))
+        .unwrap_or_default()
+}
+
+/// Apply a ledger entry to the user's account. Outcalls are debited, all
+/// other kinds credited. A debit beyond the balance is recorded as overdraft,
+/// which credits pay off first. Returns the new balance.
+fn book_cycles(user_id: &str, kind: CyclesEntryKind, cycles: u64, cycles_attached: Option<u64>) -> u64 {
+    let mut account = cycles_account(user_id);
+    match kind {
+        CyclesEntryKind::Outcall { .. } => {
+            account.debit(cycles);
+            account.total_spent += cycles;
+        }
+        CyclesEntryKind::OutcallRefund { .. } => {
+            account.credit(cycles);
+            account.total_spent = account.total_spent.saturating_sub(cycles);
+        }
+        CyclesEntryKind::Deposit | CyclesEntryKind::Grant => account.credit(cycles),
+    }
+    let balance = account.balance;
+    STABLE_CYCLES_ACCOUNTS.with_borrow_mut(|accounts| accounts.insert(user_id.to_string(), account));
+
+    STABLE_CYCLES_LEDGER.with_borrow_mut(|ledger| {
+        let id = ledger.last_key_value().map_or(0, |(id, _)| id + 1);
+        ledger.insert(
+            id,
+            CyclesLedgerEntry {
+                id,
+                user_id: user_id.to_string(),
+                kind,
+                cycles,
+                cycles_attached,
+                balance_after: balance,
+                timestamp: time(),
+            },
+        );
+    });
+    balance
+}
+
+/// Response limit used when none is configured
+const DEFAULT_MAX_RESPONSE_BYTES: u64 = 256 * 1024;
+
+/// Largest response the IC allows for an HTTP outcall
+const MAX_RESPONSE_BYTES_LIMIT: u64 = 2_000_000;
+
+/// Nodes in a standard application subnet
+const DEFAULT_SUBNET_SIZE: u64 = 13;
+
+/// Largest subnet `set_subnet_size` accepts, well above any subnet on the IC
+const MAX_SUBNET_SIZE: u64 = 100;
+
+/// Used when no endpoint is configured for `DetectionProvider::ShootifyProxy`
+const SHOOTIFY_PROXY_URL: &str = "https://icp-api.shootify.io/api/v1/utils/icp-proxy/";
+
+/// Query parameters besides `utm_*` that only track where a visitor came from
+const TRACKING_PARAMS: [&str; 8] = [
+    "fbclid", "gclid", "dclid", "msclkid", "igshid", "mc_cid", "mc_eid", "_ga",
+];
+
+fn max_response_bytes() -> u64 {
+    config().max_response_bytes.unwrap_or(DEFAULT_MAX_RESPONSE_BYTES)
+}
+
+/// Cycles to attach to an HTTP outcall, following the IC pricing formula
+/// `(3_000_000 + 60_000 * n) * n + 400 * n * request_bytes + 800 * n * max_response_bytes`
+/// for a subnet of `n` nodes. The request size covers the URL, headers, body
+/// and transform; anything not used is refunded after the call.
+fn outcall_cost(request: &CanisterHttpRequestArgument, subnet_size: u64) -> u64 {
+    let headers_bytes: usize = request
+        .headers
+        .iter()
+        .map(|header| header.name.len() + header.value.len())
+        .sum();
+    let transform_bytes = request
+        .transform
+        .as_ref()
+        .map_or(0, |transform| transform.function.0.method.len() + transform.context.len());
+    let request_bytes = (request.url.len()
+        + headers_bytes
+        + request.body.as_ref().map_or(0, Vec::len)
+        + transform_bytes) as u64;
+    let response_bytes = request.max_response_bytes.unwrap_or(MAX_RESPONSE_BYTES_LIMIT);
+
+    // Saturate rather than wrap, so an oversized request can never look cheap
+    let n = subnet_size;
+    n.saturating_mul(60_000)
+        .saturating_add(3_000_000)
+        .saturating_mul(n)
+        .saturating_add(n.saturating_mul(400).saturating_mul(request_bytes))
+        .saturating_add(n.saturating_mul(800).saturating_mul(response_bytes))
+}
+
+/// Make an HTTP outcall paid for by `payer`. The attached cycles are debited
+/// before the call, like `with_crawl_credit` takes its credit, so concurrent
+/// crawls cannot all pass the prepaid check; the refund is credited back.
+async fn paid_http_request(
+    payer: &str,
+    image_name: &str,
+    request: CanisterHttpRequestArgument,
+) -> Result<HttpResponse, CrawlError> {
+    let cycles = outcall_cost(&request, config().subnet_size.unwrap_or(DEFAULT_SUBNET_SIZE));
+    ic_cdk::println!("Estimated cycles: '{}'", cycles);
+
+    if config().require_prepaid_cycles == Some(true) {
+        let balance = cycles_account(payer).balance;
+        if balance < cycles {
+            return Err(CrawlError::Permanent(SentinelError::insufficient_funds(format!(
+                "Insufficient cycles: The crawl needs {} cycles but your balance is {}.",
+                cycles, balance
+            ))));
+        }
+    }
+    let image_name = image_name.to_string();
+    book_cycles(
+        payer,
+        CyclesEntryKind::Outcall {
+            image_name: image_name.clone(),
+        },
+        cycles,
+        Some(cycles),
+    );
+
+    let (sent, result) = await_outcall(http_request(request, cycles.into())).await;
+    // A call that was not sent resolves without a reply or reject callback,
+    // where `msg_cycles_refunded128` traps; none of its cycles were spent
+    let refunded = if sent {
+        u64::try_from(msg_cycles_refunded128()).unwrap_or(cycles).min(cycles)
+    } else {
+        cycles
+    };
+    if refunded > 0 {
+        book_cycles(payer, CyclesEntryKind::OutcallRefund { image_name }, refunded, None);
+    }
+
+    let response = match result {
+        Ok((response,)) => response,
+        Err((code, message)) => {
+            let err = SentinelError::outcall_failed(
+                code as u32,
+                format!("HTTP request failed. RejectionCode: {code:?}, Error: {message}"),
+            );
+            // Timeouts and unreachable hosts are reported as SysTransient
+            return Err(match code {
+                RejectionCode::SysTransient => CrawlError::Transient(err),
+                _ => CrawlError::Permanent(err),
+            });
+        }
+    };
+
+    let status = u32::try_from(response.status.0.clone()).unwrap_or(u32::MAX);
+    let err = SentinelError::outcall_failed(status, format!("Proxy returned HTTP {}.", status));
+    if status == 429 || status >= 500 {
+        return Err(CrawlError::Transient(err));
+    }
+    if !(200..300).contains(&status) {
+        return Err(CrawlError::Permanent(err));
+    }
+    Ok(response)
+}
+
+/// Await an inter-canister call and tell whether it was sent. ic-cdk resolves
+/// a call on its first poll only when `call_perform` failed, whatever the
+/// rejection code; a sent call always waits for its reply or reject callback.
+async fn await_outcall<T>(call: impl std::future::Future<Output = T>) -> (bool, T) {
+    let mut call = std::pin::pin!(call);
+    match futures::poll!(call.as_mut()) {
+        std::task::Poll::Ready(result) => (false, result),
+        std::task::Poll::Pending => (true, call.await),
+    }
+}
+
+fn crawl_credits(user_id: &str) -> u64 {
+    STABLE_CRAWL_CREDITS
+        .with_borrow(|credits| credits.get(&user_id.to_string()))
+        .unwrap_or(0)
+}
+
+fn set_crawl_credits(user_id: &str, credits: u64) {
+    STABLE_CRAWL_CREDITS.with_borrow_mut(|all| all.insert(user_id.to_string(), credits));
+}
+
+/// Run a crawl for `cost` crawl credits of `user_id` when payments are
+/// enabled, one per provider queried. The credits are taken before the
+/// outcalls so that concurrent crawls cannot spend them twice, and given back
+/// for every provider that failed. Returns the crawl's JSON response.
+async fn with_crawl_credit<E: From<SentinelError>>(
+    user_id: &str,
+    cost: u64,
+    crawl: impl std::future::Future<Output = Result<Detection, E>>,
+) -> Result<String, E> {
+    if config().payment_ledger.is_none() {
+        return crawl.await.map(|detection| detection.response);
+    }
+
+    let credits = crawl_credits(user_id);
+    if credits < cost {
+        let err = SentinelError::insufficient_funds(format!(
+            "Not enough crawl credits: The crawl needs {} but you have {}. Buy credits with buy_crawl_credits.",
+            cost, credits
+        ));
+        return Err(err.into());
+    }
+    set_crawl_credits(user_id, credits - cost);
+
+    let result = crawl.await;
+    let refund = result.as_ref().map_or(cost, |detection| detection.failed_providers.min(cost));
+    if refund > 0 {
+        set_crawl_credits(user_id, crawl_credits(user_id) + refund);
+    }
+    result.map(|detection| detection.response)
+}
+
+/// Content may only be registered for subjects with an active consent record
+fn ensure_active_consent(subject_id: &str) -> Result<(), SentinelError> {
+    let consent = STABLE_CONSENTS.with_borrow(|consents| consents.get(&subject_id.to_string()));
+    match consent {
+        Some(consent) if consent.is_active(time()) => Ok(()),
+        Some(consent) if consent.revoked_at.is_some() => {
+            Err(SentinelError::failed_precondition(format!(
+                "Consent for subject '{}' has been revoked.",
+                subject_id
+            )))
+        }
+        Some(_) => Err(SentinelError::failed_precondition(format!(
+            "Consent for subject '{}' has expired.",
+            subject_id
+        ))),
+        None => Err(SentinelError::failed_precondition(format!(
+            "No consent recorded for subject '{}'.",
+            subject_id
+        ))),
+    }
+}
+
+/// When consent covering an image's content was revoked: either that of the
+/// subject the image was stored for or of a subject the prediction's hashes
+/// are registered to
+fn consent_revoked_at(subject_id: Option<&str>, prediction_id: &str) -> Option<u64> {
+    let subject_revoked_at = subject_id
+        .and_then(|subject_id| {
+            STABLE_CONSENTS.with_borrow(|consents| consents.get(&subject_id.to_string()))
+        })
+        .and_then(|consent| consent.revoked_at);
+
+    let image_hashes = STABLE_PREDICTION_INDEX
+        .with_borrow(|index| index.get(&prediction_id.to_string()))
+        .map(|entry| entry.image_hashes)
+        .unwrap_or_default();
+    let hash_revoked_at = image_hashes
+        .iter()
+        .flat_map(|image_hash| {
+            subjects_for_hash(image_hash)
+                .into_iter()
+                .filter_map(move |subject_id| subject_hash_revoked_at(&subject_id, image_hash))
+        })
+        .min();
+
+    match (subject_revoked_at, hash_revoked_at) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+fn subject_hash_revoked_at(subject_id: &str, image_hash: &str) -> Option<u64> {
+    STABLE_SUBJECT_IMAGES.with_borrow(|subject_images| {
+        subject_images
+            .get(&subject_id.to_string())?
+            .entries
+            .into_iter()
+            .find(|entry| entry.image_hash == image_hash)?
+            .revoked_at
+    })
+}
+
+/// Active grants `grantee` holds with the given permission
+fn active_grants(grantee: &str, permission: Permission) -> Vec<AccessGrant> {
+    let now = time();
+    STABLE_ACCESS_GRANTS.with_borrow(|grants| {
+        grants
+            .values()
+            .filter(|grant| grant.grantee == grantee && grant.is_active(now))
+            .filter(|grant| grant.permissions.contains(&permission))
+            .collect()
+    })
+}
+
+/// Whether `user_id` may act on an image of `owner_user_id`, either as its
+/// owner, through its role in the owner's organization or through a grant
+fn has_access(user_id: &str, owner_user_id: &str, image_name: &str, permission: Permission) -> bool {
+    user_id == owner_user_id
+        || model_organization(owner_user_id)
+            .and_then(|org| org.role_of(user_id))
+            .is_some_and(|role| role.allows(permission))
+        || active_grants(user_id, permission)
+            .iter()
+            .any(|grant| grant.covers(owner_user_id, image_name))
+}
+
+/// The organization a model belongs to; a model can be in at most one
+fn model_organization(model_user_id: &str) -> Option<Organization> {
+    STABLE_ORGANIZATIONS.with_borrow(|orgs| {
+        orgs.values()
+            .find(|org| org.models.iter().any(|model| model == model_user_id))
+    })
+}
+
+/// Run `crawl` with `crawls` crawls counted against the budget of the image
+/// owner's organization. As with `with_crawl_quota`, they are counted before
+/// the outcalls and given back for the providers that failed.
+async fn with_org_crawl_budget<E: From<SentinelError>>(
+    owner_user_id: &str,
+    crawls: u64,
+    crawl: impl std::future::Future<Output = Result<Detection, E>>,
+) -> Result<Detection, E> {
+    let Some(mut org) = model_organization(owner_user_id) else {
+        return crawl.await;
+    };
+    org.roll_budget_period(time());
+    if org.crawl_budget.is_some_and(|budget| org.crawls_used + crawls > budget) {
+        let err = SentinelError::quota_exceeded(format!(
+            "Crawl budget of organization '{}' is exhausted for the current period.",
+            org.org_id
+        ));
+        return Err(err.into());
+    }
+    org.crawls_used += crawls;
+    let (org_id, period_start) = (org.org_id.clone(), org.budget_period_start);
+    save_org(org);
+
+    let result = crawl.await;
+    let failed = result.as_ref().map_or(crawls, |detection| detection.failed_providers);
+    if failed > 0 {
+        release_org_crawls(&org_id, period_start, failed);
+    }
+    result
+}
+
+/// Give back crawls counted against an organization's budget, unless a new
+/// budget period has started since
+fn release_org_crawls(org_id: &str, period_start: u64, crawls: u64) {
+    let org = STABLE_ORGANIZATIONS.with_borrow(|orgs| orgs.get(&org_id.to_string()));
+    let Some(mut org) = org else {
+        return;
+    };
+    if org.budget_period_start == period_start {
+        org.crawls_used = org.crawls_used.saturating_sub(crawls);
+        save_org(org);
+    }
+}
+
+/// Load an organization and check that `user_id` holds at least `role` in it
+fn org_for(user_id: &str, org_id: &str, role: OrgRole) -> Result<Organization, SentinelError> {
+    let Some(org) = STABLE_ORGANIZATIONS.with_borrow(|orgs| orgs.get(&org_id.to_string())) else {
+        return Err(SentinelError::not_found(format!("Organization '{}' not found.", org_id)));
+    };
+    match (org.role_of(user_id), role) {
+        (Some(OrgRole::Admin), _) | (Some(OrgRole::Member), OrgRole::Member) => Ok(org),
+        _ => Err(SentinelError::access_denied(
+            "Access denied: You are not allowed to manage this organization.",
+        )),
+    }
+}
+
+fn save_org(org: Organization) {
+    STABLE_ORGANIZATIONS.with_borrow_mut(|orgs| orgs.insert(org.org_id.clone(), org));
+}
+
+fn ensure_controller() -> Result<(), SentinelError> {
+    if ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        Ok(())
+    } else {
+        Err(SentinelError::access_denied(
+            "Access denied: Only controllers can change the configuration.",
+        ))
+    }
+}
+
+/// Whether `caller` may act for a user or subject ID: controllers always may,
+/// anyone else only as the principal linked to the ID
+fn may_act_for(caller: &Principal, is_controller: bool, id: &str) -> bool {
+    is_controller
+        || STABLE_PRINCIPALS.with_borrow(|principals| principals.get(&id.to_string())) == Some(*caller)
+}
+
+/// User IDs are chosen by the caller; bind them to the calling principal
+fn ensure_caller_acts_for(id: &str) -> Result<(), SentinelError> {
+    let caller = ic_cdk::caller();
+    if may_act_for(&caller, ic_cdk::api::is_controller(&caller), id) {
+        Ok(())
+    } else {
+        Err(SentinelError::access_denied(format!(
+            "Access denied: The caller may not act for '{}'.",
+            id
+        )))
+    }
+}
+
+/// Whether `caller` may remove hashes registered for a subject: the principal
+/// that signed the subject's consent, or a controller
+fn may_manage_subject(caller: &Principal, is_controller: bool, subject_id: &str) -> bool {
+    is_controller
+        || STABLE_CONSENTS
+            .with_borrow(|consents| consents.get(&subject_id.to_string()))
+            .is_some_and(|consent| consent.model_principal == caller.to_text())
+}
+
+fn caller_manages_subject(subject_id: &str) -> bool {
+    let caller = ic_cdk::caller();
+    may_manage_subject(&caller, ic_cdk::api::is_controller(&caller), subject_id)
+}
+
+fn ensure_subject_manager(subject_id: &str) -> Result<(), SentinelError> {
+    if caller_manages_subject(subject_id) {
+        Ok(())
+    } else {
+        Err(SentinelError::access_denied(
+            "Access denied: Only the consent signer can change this subject's hashes.",
+        ))
+    }
+}
+
+/// Revoked hashes are kept as evidence of unauthorized use
+fn ensure_not_revoked(subject_id: &str, image_hash: &str) -> Result<(), SentinelError> {
+    if subject_hash_revoked_at(subject_id, image_hash).is_some() {
+        return Err(SentinelError::failed_precondition(format!(
+            "Image hash '{}' of subject '{}' has been revoked and cannot be removed.",
+            image_hash, subject_id
+        )));
+    }
+    Ok(())
+}
+
+/// Maximum number of ids accepted by the batched lookup endpoints
+const MAX_BATCH_SIZE: usize = 100;
+
+/// Apply `update` to the index entry of a prediction, dropping the entry once it is empty
+fn update_prediction_index(prediction_id: &str, update: impl FnOnce(&mut PredictionIndexEntry)) {
+    if prediction_id.is_empty() {
+        return;
+    }
+
+    STABLE_PREDICTION_INDEX.with_borrow_mut(|index| {
+        let key = prediction_id.to_string();
+        let mut entry = index.get(&key).unwrap_or_default();
+        update(&mut entry);
+        if entry.is_empty() {
+            index.remove(&key);
+        } else {
+            index.insert(key, entry);
+        }
+    });
+}
+
+/// Default and maximum number of domains returned by `top_offending_domains`
+const DEFAULT_DOMAIN_PAGE_SIZE: u64 = 20;
+const MAX_DOMAIN_PAGE_SIZE: u64 = 500;
+
+/// Number of matches per domain in a crawl result. Visually similar images
+/// are not copies and do not count.
+fn matched_domains(result: &CrawlResult) -> HashMap<String, u64> {
+    let mut domains: HashMap<String, u64> = HashMap::new();
+    match &result.matches {
+        Some(matches) => {
+            for found in matches.iter().filter(|m| m.match_type != MatchType::VisuallySimilar) {
+                let domain = url_domain(found.page_url.as_deref().unwrap_or(&found.url));
+                *domains.entry(domain).or_default() += 1;
+            }
+        }
+        // Results crawled before matches were recorded
+        None => {
+            for url in result.full_matching_images.iter().chain(&result.pages_with_matching_images) {
+                *domains.entry(url_domain(url)).or_default() += 1;
+            }
+        }
+    }
+    domains.remove("");
+    domains
+}
+
+/// Apply `update` to the index entry of a domain, dropping the entry once it is empty
+fn update_domain_index(domain: &str, update: impl FnOnce(&mut DomainIndexEntry)) {
+    STABLE_DOMAIN_INDEX.with_borrow_mut(|index| {
+        let key = domain.to_string();
+        let mut entry = index.get(&key).unwrap_or_default();
+        update(&mut entry);
+        if entry.images.is_empty() {
+            index.remove(&key);
+        } else {
+            index.insert(key, entry);
+        }
+    });
+}
+
+fn index_crawl_domains(crawl_key: &str, result: &CrawlResult) {
+    let subject_id = crawl_key.split_once(':').and_then(|(owner, name)| {
+        STABLE_IMAGES
+            .with_borrow(|images| images.get(&name.to_string()))
+            .filter(|image| image.uploaded_by == owner)
+            .and_then(|image| image.subject_id)
+    });
+    for (domain, matches) in matched_domains(result) {
+        update_domain_index(&domain, |entry| {
+            entry.images.retain(|image| image.crawl_key != crawl_key);
+            entry.images.push(DomainImage {
+                crawl_key: crawl_key.to_string(),
+                matches,
+                subject_id: subject_id.clone(),
+                prediction_id: Some(result.prediction_id.clone()),
+            });
+        });
+    }
+}
+
+fn unindex_crawl_domains(crawl_key: &str, result: &CrawlResult) {
+    for domain in matched_domains(result).into_keys() {
+        update_domain_index(&domain, |entry| {
+            entry.images.retain(|image| image.crawl_key != crawl_key)
+        });
+    }
+}
+
+fn rebuild_domain_index() {
+    STABLE_DOMAIN_INDEX.with_borrow_mut(|index| index.clear_new());
+    STABLE_CRAWL_RESULTS.with_borrow(|results| {
+        for (key, result) in results.iter() {
+            index_crawl_domains(&key, &result);
+        }
+    });
+}
+
+fn push_unique(values: &mut Vec<String>, value: &str) {
+    if !values.iter().any(|v| v == value) {
+        values.push(value.to_string());
+    }
+}
+
+fn record_subject_event(subject_id: &str, action: SubjectAction, image_hashes: Vec<String>) {
+    let event = SubjectAuditEvent {
+        action,
+        image_hashes,
+        caller: ic_cdk::caller().to_text(),
+        timestamp: time(),
+    };
+
+    STABLE_SUBJECT_AUDIT.with_borrow_mut(|audit| {
+        let key = subject_id.to_string();
+        let mut trail = audit.get(&key).unwrap_or_default();
+        trail.events.push(event);
+        audit.insert(key, trail);
+    });
+}
+
+/// Register a hash for a subject, keeping the hash -> subjects index in sync.
+/// Returns `false` if the subject already had the hash.
+fn link_subject_hash(subject_id: &str, entry: SubjectImageEntry) -> bool {
+    let image_hash = entry.image_hash.clone();
+    let entry_for_block = entry.clone();
+    let added = STABLE_SUBJECT_IMAGES.with_borrow_mut(|subject_images| {
+        let key = subject_id.to_string();
+        let mut subject = subject_images.get(&key).unwrap_or_default();
+        if subject.contains(&image_hash) {
+            return false;
+        }
+        subject.entries.push(entry);
+        subject_images.insert(key, subject);
+        true
+    });
+
+    if added {
+        STABLE_HASH_SUBJECTS.with_borrow_mut(|hash_subjects| {
+            let mut subjects = hash_subjects.get(&image_hash).unwrap_or_default();
+            push_unique(&mut subjects.images, subject_id);
+            hash_subjects.insert(image_hash, subjects);
+        });
+        append_hash_block(BTYPE_REGISTER_HASH, subject_id, &entry_for_block);
+    }
+    added
+}
+
+/// Remove a hash from a subject, dropping the subject once it has no hashes
+/// left. Returns the removed entry, or `None` if the subject did not have the
+/// hash. Revoked entries are never removed: they keep later matches flagged
+/// as unauthorized.
+fn unlink_subject_hash(subject_id: &str, image_hash: &str) -> Option<SubjectImageEntry> {
+    let removed = STABLE_SUBJECT_IMAGES.with_borrow_mut(|subject_images| {
+        let key = subject_id.to_string();
+        let mut subject = subject_images.get(&key)?;
+        let position = subject
+            .entries
+            .iter()
+            .position(|entry| entry.image_hash == image_hash && entry.revoked_at.is_none())?;
+        let removed = subject.entries.remove(position);
+        if subject.entries.is_empty() {
+            subject_images.remove(&key);
+        } else {
+            subject_images.insert(key, subject);
+        }
+        Some(removed)
+    });
+
+    if let Some(entry) = &removed {
+        append_hash_block(BTYPE_UNREGISTER_HASH, subject_id, entry);
+        STABLE_HASH_SUBJECTS.with_borrow_mut(|hash_subjects| {
+            let key = image_hash.to_string();
+            if let Some(mut subjects) = hash_subjects.get(&key) {
+                subjects.images.retain(|subject| subject != subject_id);
+                if subjects.images.is_empty() {
+                    hash_subjects.remove(&key);
+                } else {
+                    hash_subjects.insert(key, subjects);
+                }
+            }
+        });
+    }
+    removed
+}
+
+/// Rewrite every subject so entries still stored as bare hash lists are
+/// persisted in the current format
+fn migrate_subject_images() {
+    let subjects: Vec<(String, SubjectImages)> =
+        STABLE_SUBJECT_IMAGES.with_borrow(|subject_images| subject_images.iter().collect());
+
+    STABLE_SUBJECT_IMAGES.with_borrow_mut(|subject_images| {
+        for (subject_id, subject) in subjects {
+            subject_images.insert(subject_id, subject);
+        }
+    });
+}
+
+fn subjects_for_hash(image_hash: &str) -> Vec<String> {
+    STABLE_HASH_SUBJECTS.with_borrow(|hash_subjects| {
+        hash_subjects
+            .get(&image_hash.to_string())
+            .map(|entry| entry.images)
+            .unwrap_or_default()
+    })
+}
+
+/// Rebuild the hash -> subjects index from STABLE_SUBJECT_IMAGES
+fn rebuild_hash_subjects_index() {
+    let subjects: Vec<(String, SubjectImages)> =
+        STABLE_SUBJECT_IMAGES.with_borrow(|subject_images| subject_images.iter().collect());
+
+    STABLE_HASH_SUBJECTS.with_borrow_mut(|hash_subjects| {
+        hash_subjects.clear_new();
+        for (subject_id, subject) in subjects {
+            for image_hash in subject.hashes() {
+                let mut subjects = hash_subjects.get(&image_hash).unwrap_or_default();
+                push_unique(&mut subjects.images, &subject_id);
+                hash_subjects.insert(image_hash, subjects);
+            }
+        }
+    });
+}
+
+/// Drop hashes that are no longer registered for any subject from the prediction index
+fn forget_unregistered_hashes(image_hashes: &[String]) {
+    let unregistered: Vec<&String> = STABLE_HASH_SUBJECTS.with_borrow(|hash_subjects| {
+        image_hashes
+            .iter()
+            .filter(|hash| !hash_subjects.contains_key(hash))
+            .collect()
+    });
+    if unregistered.is_empty() {
+        return;
+    }
+
+    let affected: Vec<String> = STABLE_PREDICTION_INDEX.with_borrow(|index| {
+        index
+            .iter()
+            .filter(|(_, entry)| entry.image_hashes.iter().any(|h| unregistered.contains(&h)))
+            .map(|(prediction_id, _)| prediction_id)
+            .collect()
+    });
+    for prediction_id in affected {
+        update_prediction_index(&prediction_id, |entry| {
+            entry.image_hashes.retain(|h| !unregistered.contains(&h))
+        });
+    }
+}
+
+/// Rebuild the image and crawl result parts of the prediction index from the
+/// primary maps. Hashes only live in the index, so they are carried over.
+fn rebuild_prediction_index() {
+    let hashes: Vec<(String, Vec<String>)> = STABLE_PREDICTION_INDEX.with_borrow(|index| {
+        index
+            .iter()
+            .filter(|(_, entry)| !entry.image_hashes.is_empty())
+            .map(|(key, entry)| (key, entry.image_hashes))
+            .collect()
+    });
+    STABLE_PREDICTION_INDEX.with_borrow_mut(|index| index.clear_new());
+
+    for (prediction_id, image_hashes) in hashes {
+        update_prediction_index(&prediction_id, |entry| entry.image_hashes = image_hashes);
+    }
+
+    let images: Vec<(String, String)> = STABLE_IMAGES.with_borrow(|images| {
+        images
+            .iter()
+            .map(|(name, image)| (image.prediction_id, name))
+            .collect()
+    });
+    for (prediction_id, name) in images {
+        update_prediction_index(&prediction_id, |entry| push_unique(&mut entry.image_names, &name));
+    }
+
+    let crawls: Vec<(String, String)> = STABLE_CRAWL_RESULTS.with_borrow(|results| {
+        results
+            .iter()
+            .map(|(key, result)| (result.prediction_id, key))
+            .collect()
+    });
+    for (prediction_id, key) in crawls {
+        update_prediction_index(&prediction_id, |entry| push_unique(&mut entry.crawl_keys, &key));
+    }
+}
+
+fn start_timers() {
+    ic_cdk_timers::set_timer_interval(TRASH_PURGE_INTERVAL, purge_expired_trash);
+    ic_cdk_timers::set_timer_interval(CRAWL_QUEUE_INTERVAL, process_crawl_queue);
+    ic_cdk_timers::set_timer_interval(CRAWL_JOB_PRUNE_INTERVAL, prune_finished_crawl_jobs);
+}
+
+/// Layout version the code expects; bump it when adding a step to `migrate_stable_data`
+const SCHEMA_VERSION: u64 = 5;
+
+fn set_schema_version(version: u64) {
+    STABLE_SCHEMA_VERSION.with_borrow_mut(|cell| {
+        if let Err(e) = cell.set(version) {
+            ic_cdk::trap(&format!("Failed to store schema version: {:?}", e));
+        }
+    });
+}
+
+/// Bring stored data up to `SCHEMA_VERSION`. Each step runs on one upgrade
+/// only; afterwards the endpoints keep the indexes in sync on every write.
+fn migrate_stable_data() {
+    let version = STABLE_SCHEMA_VERSION.with_borrow(|cell| *cell.get());
+    if version < 1 {
+        // Indexes introduced before the schema version existed
+        rebuild_prediction_index();
+        rebuild_domain_index();
+        migrate_subject_images();
+        rebuild_hash_subjects_index();
+    }
+    if version < 2 {
+        rebuild_storage_usage();
+    }
+    if version < 3 {
+        rebuild_active_crawl_jobs();
+    }
+    if version < 4 {
+        rebuild_domain_index();
+    }
+    if version < 5 {
+        retire_portfolio_scopes();
+    }
+    if version != SCHEMA_VERSION {
+        set_schema_version(SCHEMA_VERSION);
+    }
+}
+
+#[ic_cdk::init]
+fn init() {
+    // A fresh canister has nothing to migrate
+    set_schema_version(SCHEMA_VERSION);
+    start_timers();
+}
+
+#[ic_cdk::post_upgrade]
+fn post_upgrade() {
+    migrate_stable_data();
+    certify_tip();
+    requeue_interrupted_crawl_jobs();
+    start_timers();
+}
+
+/// Store a crawl result
+fn store_crawl_result(user_id: String, image_name: String, mut result: CrawlResult) -> Result<(), SentinelError> {
+    let key = format!("{}:{}", user_id, image_name);
+
+    result.set_last_update_to_now();
+    let prediction_id = result.prediction_id.clone();
+
+    if let Some(previous) = STABLE_CRAWL_RESULTS.with_borrow(|results| results.get(&key)) {
+        unindex_crawl_domains(&key, &previous);
+    }
+    index_crawl_domains(&key, &result);
+
+    STABLE_CRAWL_RESULTS.with_borrow_mut(|results| {
+        results.insert(key.clone(), result);
+        ic_cdk::println!("Crawl result stored for key '{}'", key);
+    });
+    update_prediction_index(&prediction_id, |entry| push_unique(&mut entry.crawl_keys, &key));
+
+    Ok(())
+}
+
+/// Subjects whose registered hashes are linked to a prediction
+fn prediction_subjects(prediction_id: &str) -> Vec<String> {
+    let image_hashes = STABLE_PREDICTION_INDEX
+        .with_borrow(|index| index.get(&prediction_id.to_string()))
+        .map(|entry| entry.image_hashes)
+        .unwrap_or_default();
+    let mut subject_ids = Vec::new();
+    for image_hash in &image_hashes {
+        for subject_id in subjects_for_hash(image_hash) {
+            push_unique(&mut subject_ids, &subject_id);
+        }
+    }
+    subject_ids
+}
+
+/// Store an image associated with a user ID. `subject_id` names the person
+/// depicted; their consent, and that of every subject linked through the
+/// prediction's registered hashes, must be active. An image without any
+/// subject is refused.
+#[ic_cdk::update]
+fn store_image(
+    user_id: String,
+    prediction_id: String,
+    name: String,
+    content: Vec<u8>,
+    subject_id: Option<String>,
+) -> Result<(), SentinelError> {
+    AuditCall::begin(
+        "store_image",
+        Some(&user_id),
+        format!(
+            "prediction_id: {}, name: {}, content: {} bytes, subject_id: {:?}",
+            prediction_id,
+            name,
+            content.len(),
+            subject_id
+        ),
+    )
+    .run(move || {
+        if user_id.is_empty() {
+            return Err(SentinelError::invalid("user_id", "User ID cannot be empty."));
+        }
+        if name.is_empty() {
+            return Err(SentinelError::invalid("name", "Image name cannot be empty."));
+        }
+        if content.is_empty() {
+            return Err(SentinelError::invalid("content", "Image content cannot be empty."));
+        }
+        let mut subject_ids = prediction_subjects(&prediction_id);
+        if let Some(subject_id) = &subject_id {
+            push_unique(&mut subject_ids, subject_id);
+        }
+        if subject_ids.is_empty() {
+            return Err(SentinelError::invalid(
+                "subject_id",
+                "Name the subject depicted, or register the prediction's hashes first.",
+            ));
+        }
+        for subject_id in &subject_ids {
+            ensure_active_consent(subject_id)?;
+        }
+        ensure_storage_quota(&user_id, 1, content.len() as u64)?;
+        // The trash is keyed by name too; a new image must not replace a restorable one
+        if STABLE_TRASH.with_borrow(|trash| trash.contains_key(&name)) {
+            return Err(SentinelError::already_exists(format!(
+                "An image with the name '{}' is in the trash.",
+                name
+            )));
+        }
+
+        let image = StoredImage {
+            content,
+            prediction_id: prediction_id.clone(),
+            uploaded_by: user_id.clone(),
+            last_error: None,
+            subject_id,
+        };
+        STABLE_IMAGES.with_borrow_mut(|images| {
+            if images.contains_key(&name) {
+                Err(SentinelError::already_exists(format!(
+                    "An image with the name '{}' already exists.",
+                    name
+                )))
+            } else {
+                images.insert(name.clone(), image.clone());
+                ic_cdk::println!("Image '{}' stored successfully by user '{}'", name, user_id);
+                Ok(())
+            }
+        })?;
+        track_image_storage(&image, true);
+        append_image_block(BTYPE_REGISTER_IMAGE, &name, &image);
+
+        update_prediction_index(&prediction_id, |entry| push_unique(&mut entry.image_names, &name));
+        Ok(())
+    })
+}
+
+fn register_hash(subject_id: String, registration: HashRegistration) -> Result<(), SentinelError> {
+    if subject_id.is_empty() {
+        return Err(SentinelError::invalid("subject_id", "Subject ID cannot be empty."));
+    }
+    if registration.image_hash.is_empty() {
+        return Err(SentinelError::invalid("image_hash", "Image hash cannot be empty."));
+    }
+    ensure_active_consent(&subject_id)?;
+
+    let entry = registration.into_entry();
+    let image_hash = entry.image_hash.clone();
+    let prediction_id = entry.prediction_id.clone();
+
+    if !link_subject_hash(&subject_id, entry) {
+        return Err(SentinelError::already_exists(format!(
+            "Image hash '{}' already exists for subject ID '{}'.",
+            image_hash, subject_id
+        )));
+    }
+    ic_cdk::println!("Added image_hash '{}' to subject_id '{}'", image_hash, subject_id);
+
+    if let Some(prediction_id) = prediction_id {
+        update_prediction_index(&prediction_id, |entry| push_unique(&mut entry.image_hashes, &image_hash));
+    }
+    record_subject_event(&subject_id, SubjectAction::HashAdded, vec![image_hash]);
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn add_image_hash(subject_id: String, image_hash: String) -> Result<(), SentinelError> {
+    AuditCall::begin(
+        "add_image_hash",
+        Some(&subject_id),
+        format!("image_hash: {}", image_hash),
+    )
+    .run(move || {
+        register_hash(
+            subject_id,
+            HashRegistration {
+                image_hash,
+                algorithm: HashAlgorithm::Unknown,
+                prediction_id: None,
+                image_name: None,
+            },
+        )
+    })
+}
+
+/// Register an image hash for a subject together with its algorithm and origin
+#[ic_cdk::update]
+fn register_image_hash(subject_id: String, registration: HashRegistration) -> Result<(), SentinelError> {
+    AuditCall::begin(
+        "register_image_hash",
+        Some(&subject_id),
+        format!(
+            "image_hash: {}, algorithm: {:?}, prediction_id: {:?}, image_name: {:?}",
+            registration.image_hash, registration.algorithm, registration.prediction_id, registration.image_name
+        ),
+    )
+    .run(move || {
+        register_hash(subject_id, registration)
+    })
+}
+
+/// Remove a single hash registered by mistake from a subject
+#[ic_cdk::update]
+fn remove_image_hash(subject_id: String, image_hash: String) -> Result<(), SentinelError> {
+    AuditCall::begin(
+        "remove_image_hash",
+        Some(&subject_id),
+        format!("image_hash: {}", image_hash),
+    )
+    .run(move || {
+        if subject_id.is_empty() {
+            return Err(SentinelError::invalid("subject_id", "Subject ID cannot be empty."));
+        }
+        if image_hash.is_empty() {
+            return Err(SentinelError::invalid("image_hash", "Image hash cannot be empty."));
+        }
+
+        if !STABLE_SUBJECT_IMAGES.with_borrow(|subject_images| subject_images.contains_key(&subject_id)) {
+            return Err(SentinelError::not_found("Subject ID not found."));
+        }
+        ensure_subject_manager(&subject_id)?;
+        ensure_not_revoked(&subject_id, &image_hash)?;
+        if unlink_subject_hash(&subject_id, &image_hash).is_none() {
+            return Err(SentinelError::not_found(format!(
+                "Image hash '{}' not found for subject ID '{}'.",
+                image_hash, subject_id
+            )));
+        }
+        ic_cdk::println!("Removed image_hash '{}' from subject_id '{}'", image_hash, subject_id);
+
+        forget_unregistered_hashes(std::slice::from_ref(&image_hash));
+        record_subject_event(&subject_id, SubjectAction::HashRemoved, vec![image_hash]);
+        Ok(())
+    })
+}
+
+/// Replace every hash registered for a subject with `image_hashes`
+#[ic_cdk::update]
+fn replace_image_hashes(subject_id: String, image_hashes: Vec<String>) -> Result<(), SentinelError> {
+    AuditCall::begin(
+        "replace_image_hashes",
+        Some(&subject_id),
+        format!("image_hashes: [{}]", image_hashes.join(", ")),
+    )
+    .run(move || {
+        if subject_id.is_empty() {
+            return Err(SentinelError::invalid("subject_id", "Subject ID cannot be empty."));
+        }
+        if image_hashes.is_empty() {
+            return Err(SentinelError::invalid(
+                "image_hashes",
+                "Image hashes cannot be empty; use delete_subject to remove a subject.",
+            ));
+        }
+        if image_hashes.iter().any(|hash| hash.is_empty()) {
+            return Err(SentinelError::invalid("image_hashes", "Image hash cannot be empty."));
+        }
+        let mut unique = Vec::with_capacity(image_hashes.len());
+        for hash in &image_hashes {
+            if unique.contains(hash) {
+                return Err(SentinelError::invalid(
+                    "image_hashes",
+                    format!("Image hash '{}' is listed more than once.", hash),
+                ));
+            }
+            unique.push(hash.clone());
+        }
+        ensure_active_consent(&subject_id)?;
+        ensure_subject_manager(&subject_id)?;
+
+        let previous = STABLE_SUBJECT_IMAGES
+            .with_borrow(|subject_images| subject_images.get(&subject_id))
+            .map(|subject| subject.hashes())
+            .unwrap_or_default();
+        for hash in previous.iter().filter(|hash| !image_hashes.contains(hash)) {
+            ensure_not_revoked(&subject_id, hash)?;
+        }
+
+        // Hashes that stay keep their original registration metadata
+        for hash in &image_hashes {
+            link_subject_hash(&subject_id, SubjectImageEntry::legacy(hash.clone()).registered_now());
+        }
+        let dropped: Vec<String> = previous
+            .into_iter()
+            .filter(|hash| !image_hashes.contains(hash))
+            .collect();
+        for hash in &dropped {
+            unlink_subject_hash(&subject_id, hash);
+        }
+        // Keep the caller's ordering rather than the link order
+        STABLE_SUBJECT_IMAGES.with_borrow_mut(|subject_images| {
+            if let Some(mut subject) = subject_images.get(&subject_id) {
+                subject.entries.sort_by_key(|entry| image_hashes.iter().position(|hash| hash == &entry.image_hash));
+                subject_images.insert(subject_id.clone(), subject);
+            }
+        });
+        ic_cdk::println!("Replaced image hashes of subject_id '{}'", subject_id);
+
+        forget_unregistered_hashes(&dropped);
+        record_subject_event(&subject_id, SubjectAction::HashesReplaced, image_hashes);
+        Ok(())
+    })
+}
+
+/// Revoke a subject's registration by removing all of its hashes
+#[ic_cdk::update]
+fn delete_subject(subject_id: String) -> Result<(), SentinelError> {
+    AuditCall::begin("delete_subject", Some(&subject_id), String::new()).run(move || {
+        if subject_id.is_empty() {
+            return Err(SentinelError::invalid("subject_id", "Subject ID cannot be empty."));
+        }
+
+        let Some(subject) = STABLE_SUBJECT_IMAGES.with_borrow(|subject_images| subject_images.get(&subject_id)) else {
+            return Err(SentinelError::not_found("Subject ID not found."));
+        };
+        ensure_subject_manager(&subject_id)?;
+        let hashes = subject.hashes();
+        for hash in &hashes {
+            ensure_not_revoked(&subject_id, hash)?;
+        }
+        for hash in &hashes {
+            unlink_subject_hash(&subject_id, hash);
+        }
+        ic_cdk::println!("Deleted subject_id '{}'", subject_id);
+
+        forget_unregistered_hashes(&hashes);
+        record_subject_event(&subject_id, SubjectAction::SubjectDeleted, hashes);
+        Ok(())
+    })
+}
+
+/// Find the subjects that registered an image hash
+#[ic_cdk::query]
+fn find_subjects_by_hash(image_hash: String) -> Result<Vec<String>, SentinelError> {
+    if image_hash.is_empty() {
+        return Err(SentinelError::invalid("image_hash", "Image hash cannot be empty."));
+    }
+
+    let subject_ids = subjects_for_hash(&image_hash);
+    if subject_ids.is_empty() {
+        Err(SentinelError::not_found("Image hash not found."))
+    } else {
+        Ok(subject_ids)
+    }
+}
+
+/// Batched `find_subjects_by_hash`; hashes without a subject come back with an empty list
+#[ic_cdk::query]
+fn find_subjects_by_hashes(image_hashes: Vec<String>) -> Result<Vec<HashSubjects>, SentinelError> {
+    if image_hashes.len() > MAX_BATCH_SIZE {
+        return Err(SentinelError::invalid(
+            "image_hashes",
+            format!(
+                "At most {} image hashes can be requested at once.",
+                MAX_BATCH_SIZE
+            ),
+        ));
+    }
+
+    Ok(image_hashes
+        .into_iter()
+        .map(|image_hash| {
+            let subject_ids = subjects_for_hash(&image_hash);
+            let revoked_subject_ids = subject_ids
+                .iter()
+                .filter(|subject_id| subject_hash_revoked_at(subject_id, &image_hash).is_some())
+                .cloned()
+                .collect();
+            HashSubjects {
+                image_hash,
+                subject_ids,
+                revoked_subject_ids,
+            }
+        })
+        .collect())
+}
+
+/// Every change made to a subject's hashes, oldest first
+#[ic_cdk::query]
+fn get_subject_audit_trail(subject_id: String) -> Result<Vec<SubjectAuditEvent>, SentinelError> {
+    if subject_id.is_empty() {
+        return Err(SentinelError::invalid("subject_id", "Subject ID cannot be empty."));
+    }
+
+    STABLE_SUBJECT_AUDIT.with_borrow(|audit| {
+        audit
+            .get(&subject_id)
+            .map(|trail| trail.events)
+            .ok_or_else(|| SentinelError::not_found("Subject ID not found."))
+    })
+}
+
+/// Register an image hash for a subject and link it to the prediction that produced it
+#[ic_cdk::update]
+fn add_prediction_image_hash(subject_id: String, image_hash: String, prediction_id: String) -> Result<(), SentinelError> {
+    AuditCall::begin(
+        "add_prediction_image_hash",
+        Some(&subject_id),
+        format!("image_hash: {}, prediction_id: {}", image_hash, prediction_id),
+    )
+    .run(move || {
+        if prediction_id.is_empty() {
+            return Err(SentinelError::invalid("prediction_id", "Prediction ID cannot be empty."));
+        }
+
+        register_hash(
+            subject_id,
+            HashRegistration {
+                image_hash,
+                algorithm: HashAlgorithm::Unknown,
+                prediction_id: Some(prediction_id),
+                image_name: None,
+            },
+        )
+    })
+}
+
+#[ic_cdk::query]
+fn get_image_hashes(subject_id: String) -> Result<Vec<SubjectImageEntry>, SentinelError> {
+    if subject_id.is_empty() {
+        return Err(SentinelError::invalid("subject_id", "Subject ID cannot be empty."));
+    }
+
+    STABLE_SUBJECT_IMAGES.with_borrow(|subject_images| {
+        if let Some(subject) = subject_images.get(&subject_id) {
+            Ok(subject.entries)
+        } else {
+            Err(SentinelError::not_found("Subject ID not found."))
+        }
+    })
+}
+
+/// Record the calling principal's consent for content to be registered under
+/// `subject_id`. Only the principal a controller linked to the subject with
+/// `link_principal` can sign it. Renewing replaces the terms; only the
+/// principal that first signed a subject's consent can change it. A revoked consent is final, as
+/// its revocation is certified and the subject's hashes stay revoked.
+#[ic_cdk::update]
+fn grant_consent(subject_id: String, terms: ConsentTerms) -> Result<ConsentRecord, SentinelError> {
+    AuditCall::begin(
+        "grant_consent",
+        Some(&subject_id),
+        format!(
+            "scopes: {:?}, brands: [{}], expires_at: {:?}",
+            terms.scopes, terms.brands.join(", "), terms.expires_at
+        ),
+    )
+    .run(move || {
+        if subject_id.is_empty() {
+            return Err(SentinelError::invalid("subject_id", "Subject ID cannot be empty."));
+        }
+        if terms.scopes.is_empty() {
+            return Err(SentinelError::invalid("terms.scopes", "Consent scopes cannot be empty."));
+        }
+        let now = time();
+        if terms.expires_at.is_some_and(|expires_at| expires_at <= now) {
+            return Err(SentinelError::invalid(
+                "terms.expires_at",
+                "Consent expiry must be in the future.",
+            ));
+        }
+
+        let caller = ic_cdk::caller();
+        if caller == candid::Principal::anonymous() {
+            return Err(SentinelError::access_denied(
+                "Access denied: Consent must be signed by an authenticated principal.",
+            ));
+        }
+        if !may_act_for(&caller, false, &subject_id) {
+            return Err(SentinelError::access_denied(format!(
+                "Access denied: Subject '{}' is not linked to the calling principal.",
+                subject_id
+            )));
+        }
+        let model_principal = caller.to_text();
+
+        STABLE_CONSENTS.with_borrow_mut(|consents| {
+            if let Some(existing) = consents.get(&subject_id) {
+                if existing.model_principal != model_principal {
+                    return Err(SentinelError::access_denied(
+                        "Access denied: Consent for this subject was signed by another principal.",
+                    ));
+                }
+                if existing.revoked_at.is_some() {
+                    return Err(SentinelError::failed_precondition(format!(
+                        "Consent for subject '{}' has been revoked and cannot be renewed.",
+                        subject_id
+                    )));
+                }
+            }
+
+            let record = ConsentRecord {
+                subject_id: subject_id.clone(),
+                model_principal,
+                scopes: terms.scopes,
+                brands: terms.brands,
+                signed_at: now,
+                expires_at: terms.expires_at,
+                revoked_at: None,
+            };
+            consents.insert(subject_id.clone(), record.clone());
+            ic_cdk::println!("Consent granted for subject_id '{}'", subject_id);
+            Ok(record)
+        })
+    })
+}
+
+/// Revoke the consent of a subject; only its signer or a controller can do
+/// this. Every hash registered for the subject is marked revoked, so future
+/// matches of that content count as unauthorized, and a notice listing the
+/// affected images and licensees is stored for follow-up.
+#[ic_cdk::update]
+fn revoke_consent(subject_id: String) -> Result<RevocationNotice, SentinelError> {
+    AuditCall::begin("revoke_consent", Some(&subject_id), String::new()).run(move || {
+        let caller = ic_cdk::caller();
+        let now = time();
+
+        let record = STABLE_CONSENTS.with_borrow_mut(|consents| {
+            let Some(mut record) = consents.get(&subject_id) else {
+                return Err(SentinelError::not_found(format!(
+                    "No consent recorded for subject '{}'.",
+                    subject_id
+                )));
+            };
+            if record.model_principal != caller.to_text() && !ic_cdk::api::is_controller(&caller) {
+                return Err(SentinelError::access_denied(
+                    "Access denied: Only the signing principal can revoke this consent.",
+                ));
+            }
+            if record.revoked_at.is_some() {
+                return Err(SentinelError::failed_precondition(format!(
+                    "Consent for subject '{}' has already been revoked.",
+                    subject_id
+                )));
+            }
+
+            record.revoked_at = Some(now);
+            consents.insert(subject_id.clone(), record.clone());
+            Ok(record)
+        })?;
+        ic_cdk::println!("Consent revoked for subject_id '{}'", subject_id);
+
+        let entries = STABLE_SUBJECT_IMAGES.with_borrow_mut(|subject_images| {
+            let Some(mut subject) = subject_images.get(&subject_id) else {
+                return Vec::new();
+            };
+            for entry in subject.entries.iter_mut().filter(|entry| entry.revoked_at.is_none()) {
+                entry.revoked_at = Some(now);
+            }
+            subject_images.insert(subject_id.clone(), subject.clone());
+            subject.entries
+        });
+
+        let mut prediction_ids = Vec::new();
+        let mut image_names = Vec::new();
+        for entry in &entries {
+            if let Some(name) = &entry.image_name {
+                push_unique(&mut image_names, name);
+            }
+            if let Some(prediction_id) = &entry.prediction_id {
+                push_unique(&mut prediction_ids, prediction_id);
+            }
+        }
+        STABLE_PREDICTION_INDEX.with_borrow(|index| {
+            for prediction_id in &prediction_ids {
+                if let Some(indexed) = index.get(prediction_id) {
+                    for name in &indexed.image_names {
+                        push_unique(&mut image_names, name);
+                    }
+                }
+            }
+        });
+        // Images stored for the subject were stored under the same consent
+        STABLE_IMAGES.with_borrow(|images| {
+            for (name, image) in images.iter() {
+                if image.subject_id.as_deref() == Some(subject_id.as_str()) {
+                    push_unique(&mut image_names, &name);
+                }
+            }
+        });
+
+        let image_hashes: Vec<String> = entries.into_iter().map(|entry| entry.image_hash).collect();
+        let notice = RevocationNotice {
+            subject_id: subject_id.clone(),
+            revoked_at: now,
+            revoked_by: caller.to_text(),
+            image_hashes: image_hashes.clone(),
+            image_names,
+            prediction_ids,
+            licensees: record.brands,
+        };
+        STABLE_REVOCATION_NOTICES.with_borrow_mut(|notices| notices.insert(subject_id.clone(), notice.clone()));
+        append_block(
+            BTYPE_REVOKE_CONSENT,
+            vec![
+                ("subject_id".to_string(), text(&subject_id)),
+                ("revoked_by".to_string(), text(&notice.revoked_by)),
+                (
+                    "image_hashes".to_string(),
+                    Icrc3Value::Array(notice.image_hashes.iter().map(|hash| text(hash)).collect()),
+                ),
+            ],
+        );
+
+        record_subject_event(&subject_id, SubjectAction::ConsentRevoked, image_hashes);
+        Ok(notice)
+    })
+}
+
+/// The notification list generated when a subject's consent was revoked
+#[ic_cdk::query]
+fn get_revocation_notice(subject_id: String) -> Result<RevocationNotice, SentinelError> {
+    STABLE_REVOCATION_NOTICES.with_borrow(|notices| {
+        notices
+            .get(&subject_id)
+            .ok_or_else(|| {
+                SentinelError::not_found(format!("No revocation recorded for subject '{}'.", subject_id))
+            })
+    })
+}
+
+#[ic_cdk::query]
+fn get_consent(subject_id: String) -> Result<ConsentRecord, SentinelError> {
+    STABLE_CONSENTS.with_borrow(|consents| {
+        consents
+            .get(&subject_id)
+            .ok_or_else(|| {
+                SentinelError::not_found(format!("No consent recorded for subject '{}'.", subject_id))
+            })
+    })
+}
+
+/// Page through the audit log, oldest first, optionally filtered by user and time range
+#[ic_cdk::query]
+fn get_audit_log(query: AuditLogQuery) -> AuditLogPage {
+    let limit = query.limit.unwrap_or(DEFAULT_AUDIT_PAGE_SIZE).clamp(1, MAX_AUDIT_PAGE_SIZE);
+
+    STABLE_AUDIT_LOG.with_borrow(|log| {
+        let mut entries = Vec::new();
+        let mut index = query.start.unwrap_or(0);
+        while index < log.len() {
+            if entries.len() as u64 == limit {
+                return AuditLogPage { entries, next: Some(index) };
+            }
+
+            let entry = log.get(index).expect("audit log index within bounds");
+            if query.to.is_some_and(|to| entry.timestamp > to) {
+                // Entries are appended in time order, nothing later can match
+                break;
+            }
+
+            let in_range = query.from.is_none_or(|from| entry.timestamp >= from);
+            let matches_user = query.user.as_ref().is_none_or(|user| {
+                &entry.caller == user || entry.user_id.as_ref() == Some(user)
+            });
+            if in_range && matches_user {
+                entries.push(IndexedAuditEntry { index, entry });
+            }
+            index += 1;
+        }
+
+        AuditLogPage { entries, next: None }
+    })
+}
+
+#[ic_cdk::query]
+fn icrc3_get_blocks(args: Vec<GetBlocksArgs>) -> GetBlocksResult {
+    STABLE_BLOCKS.with_borrow(|blocks| {
+        let log_length = blocks.len();
+        let mut result = Vec::new();
+
+        for GetBlocksArgs { start, length } in args {
+            let remaining = MAX_BLOCKS_PER_REQUEST.saturating_sub(result.len() as u64);
+            let Ok(start) = u64::try_from(start.0) else {
+                continue;
+            };
+            let length = u64::try_from(length.0).unwrap_or(u64::MAX).min(remaining);
+            let end = start.saturating_add(length).min(log_length);
+
+            for id in start..end {
+                if let Some(block) = blocks.get(id) {
+                    result.push(BlockWithId { id: Nat::from(id), block });
+                }
+            }
+        }
+
+        GetBlocksResult {
+            log_length: Nat::from(log_length),
+            blocks: result,
+            archived_blocks: Vec::new(),
+        }
+    })
+}
+
+/// All blocks live in this canister, so there are no archives
+#[ic_cdk::query]
+fn icrc3_get_archives(_args: GetArchivesArgs) -> Vec<ArchiveInfo> {
+    Vec::new()
+}
+
+#[ic_cdk::query]
+fn icrc3_get_tip_certificate() -> Option<Icrc3DataCertificate> {
+    let certificate = ic_cdk::api::data_certificate()?;
+    let (index, hash) = tip()?;
+
+    let mut hash_tree = serde_cbor::ser::Serializer::new(Vec::new());
+    hash_tree.self_describe().ok()?;
+    tip_hash_tree(index, hash).serialize(&mut hash_tree).ok()?;
+
+    Some(Icrc3DataCertificate {
+        certificate,
+        hash_tree: hash_tree.into_inner(),
+    })
+}
+
+#[ic_cdk::query]
+fn icrc3_supported_block_types() -> Vec<SupportedBlockType> {
+    [
+        BTYPE_REGISTER_IMAGE,
+        BTYPE_UNREGISTER_IMAGE,
+        BTYPE_REGISTER_HASH,
+        BTYPE_UNREGISTER_HASH,
+        BTYPE_REVOKE_CONSENT,
+        BTYPE_TRANSFER_IMAGE,
+    ]
+    .into_iter()
+    .map(|block_type| SupportedBlockType {
+        block_type: block_type.to_string(),
+        url: BLOCK_TYPES_URL.to_string(),
+    })
+    .collect()
+}
+
+/// List all crawl results for a specific user ID
+#[ic_cdk::query]
+fn get_crawl_results(user_id: String) -> Result<String, SentinelError> {
+    ic_cdk::println!("User ID received: '{}'", user_id);
+
+    if user_id.is_empty() {
+        return Err(SentinelError::invalid("user_id", "User ID is empty."));
+    }
+
+    let prefix = format!("{}:", user_id);
+
+    let mut user_results = HashMap::new();
+    STABLE_CRAWL_RESULTS.with_borrow(|results| {
+        for (key, value) in results.iter() {
+            ic_cdk::println!("Stored result key '{}'", key);
+            ic_cdk::println!("Prefix '{}'", prefix);
+            if key.starts_with(&prefix) {
+                if let Some(image_name) = key.strip_prefix(&prefix) {
+                    ic_cdk::println!("Image name '{}'", image_name);
+
+                    user_results.insert(image_name.to_string(), value.clone());
+                }
+            }
+        }
+
+        // Results shared with this user by other owners
+        for grant in active_grants(&user_id, Permission::Read) {
+            let owner_prefix = format!("{}:", grant.owner_user_id);
+            match &grant.scope {
+                ContentScope::Image(name) => {
+                    if let Some(value) = results.get(&format!("{}{}", owner_prefix, name)) {
+                        user_results.entry(name.clone()).or_insert(value);
+                    }
+                }
+                ContentScope::Subject(subject_id) => {
+                    for name in subject_image_names(&grant.owner_user_id, subject_id) {
+                        if let Some(value) = results.get(&format!("{}{}", owner_prefix, name)) {
+                            user_results.entry(name).or_insert(value);
+                        }
+                    }
+                }
+                ContentScope::Portfolio => {}
+            }
+        }
+    });
+
+    if user_results.is_empty() {
+        Err(SentinelError::not_found("No crawl results found for this user."))
+    } else {
+        match serde_json::to_string(&user_results) {
+            Ok(json_string) => {
+                ic_cdk::println!("Serialized results: '{}'", json_string);
+                Ok(json_string)
+            }
+            Err(err) => {
+                ic_cdk::println!("Failed to serialize results: {}", err);
+                Err(SentinelError::internal("Failed to serialize results."))
+            }
+        }
+    }
+}
+
+/// Every URL ever matched for an image, with when it was first and last seen
+/// and whether it has gone offline. Images of other owners need read access.
+#[ic_cdk::query]
+fn get_match_history(user_id: String, name: String) -> Result<Vec<SeenUrl>, SentinelError> {
+    let owner = match STABLE_IMAGES.with_borrow(|images| images.get(&name)) {
+        Some(image) if !has_access(&user_id, &image.uploaded_by, &name, Permission::Read) => {
+            return Err(SentinelError::access_denied("Access denied: You do not own this image."));
+        }
+        Some(image) => image.uploaded_by,
+        // Crawled content that was never stored belongs to the crawling user
+        None => user_id,
+    };
+
+    STABLE_MATCH_HISTORY
+        .with_borrow(|histories| histories.get(&format!("{}:{}", owner, name)))
+        .map(|history| history.urls)
+        .ok_or_else(|| SentinelError::not_found(format!("No match history for image '{}'.", name)))
+}
+
+/// Domains carrying the most unauthorized matches across all crawl results,
+/// then the most matched images, for spotting sites that keep reusing content.
+/// Matches are unauthorized while consent for the image's content is revoked.
+#[ic_cdk::query]
+fn top_offending_domains(limit: Option<u64>) -> Vec<DomainStats> {
+    let limit = limit.unwrap_or(DEFAULT_DOMAIN_PAGE_SIZE).clamp(1, MAX_DOMAIN_PAGE_SIZE) as usize;
+
+    let mut domains: Vec<DomainStats> = STABLE_DOMAIN_INDEX.with_borrow(|index| {
+        index
+            .iter()
+            .map(|(domain, entry)| {
+                let mut models = Vec::new();
+                let mut unauthorized_matches = 0;
+                for image in &entry.images {
+                    if let Some(subject_id) = &image.subject_id {
+                        push_unique(&mut models, subject_id);
+                    }
+                    let prediction_id = image.prediction_id.as_deref().unwrap_or_default();
+                    if consent_revoked_at(image.subject_id.as_deref(), prediction_id).is_some() {
+                        unauthorized_matches += image.matches;
+                    }
+                }
+                DomainStats {
+                    domain,
+                    images: entry.images.len() as u64,
+                    models: models.len() as u64,
+                    matches: entry.images.iter().map(|image| image.matches).sum(),
+                    unauthorized_matches,
+                }
+            })
+            .collect()
+    });
+
+    domains.sort_by(|a, b| {
+        b.unauthorized_matches
+            .cmp(&a.unauthorized_matches)
+            .then(b.images.cmp(&a.images))
+            .then(b.matches.cmp(&a.matches))
+    });
+    domains.truncate(limit);
+    domains
+}
+
+/// Retrieve an image by name, validating the user ID
+#[ic_cdk::query]
+fn get_image(user_id: String, name: String) -> Result<StoredImage, SentinelError> {
+    STABLE_IMAGES.with_borrow(|images| {
+        if let Some(image) = images.get(&name) {
+            if has_access(&user_id, &image.uploaded_by, &name, Permission::Read) {
+                Ok(image)
+            } else {
+                Err(SentinelError::access_denied("Access denied: You do not own this image."))
+            }
+        } else {
+            Err(SentinelError::not_found("Image not found."))
+        }
+    })
+}
+
+/// List all images for a specific user ID
+#[ic_cdk::query]
+fn list_images(user_id: String) -> Vec<String> {
+    STABLE_IMAGES.with_borrow(|images| {
+        images
+            .iter()
+            .filter(|(_, image)| image.uploaded_by == user_id)
+            .map(|(key, _)| key.clone())
+            .collect()
+    })
+}
+
+/// Remove an image from the live maps together with its crawl result and,
+/// once no other image shares its prediction, the subject hashes registered
+/// for that prediction. Hashes stay registered when they are revoked or the
+/// caller may not manage their subject. Returns everything needed to restore it.
+fn detach_image(name: &str, image: StoredImage) -> TrashedImage {
+    STABLE_IMAGES.with_borrow_mut(|images| images.remove(&name.to_string()));
+    append_image_block(BTYPE_UNREGISTER_IMAGE, name, &image);
+
+    let crawl_key = format!("{}:{}", image.uploaded_by, name);
+    let crawl_result = STABLE_CRAWL_RESULTS.with_borrow_mut(|results| results.remove(&crawl_key));
+    if let Some(result) = &crawl_result {
+        unindex_crawl_domains(&crawl_key, result);
+    }
+    let match_history = STABLE_MATCH_HISTORY.with_borrow_mut(|history| history.remove(&crawl_key));
+
+    let mut orphaned_hashes = Vec::new();
+    update_prediction_index(&image.prediction_id, |entry| {
+        entry.image_names.retain(|n| n != name);
+        entry.crawl_keys.retain(|k| k != &crawl_key);
+        if entry.image_names.is_empty() {
+            orphaned_hashes = std::mem::take(&mut entry.image_hashes);
+        }
+    });
+
+    let mut subject_hashes = Vec::new();
+    let mut kept_hashes = Vec::new();
+    for image_hash in orphaned_hashes {
+        for subject_id in subjects_for_hash(&image_hash) {
+            if !caller_manages_subject(&subject_id) {
+                continue;
+            }
+            if let Some(registration) = unlink_subject_hash(&subject_id, &image_hash) {
+                record_subject_event(&subject_id, SubjectAction::HashRemoved, vec![image_hash.clone()]);
+                subject_hashes.push(SubjectHash {
+                    subject_id,
+                    image_hash: image_hash.clone(),
+                    registration: Some(registration),
+                });
+            }
+        }
+        if !subjects_for_hash(&image_hash).is_empty() {
+            kept_hashes.push(image_hash);
+        }
+    }
+    // Hashes still registered keep linking later crawls of the prediction to their subjects
+    update_prediction_index(&image.prediction_id, |entry| {
+        for image_hash in &kept_hashes {
+            push_unique(&mut entry.image_hashes, image_hash);
+        }
+    });
+
+    let now = time();
+    TrashedImage {
+        image,
+        crawl_result,
+        match_history,
+        subject_hashes,
+        deleted_at: now,
+        purge_at: now,
+    }
+}
+
+/// Put a detached image and its related records back into the live maps
+fn reattach_image(name: &str, trashed: TrashedImage) {
+    let TrashedImage { image, crawl_result, match_history, subject_hashes, .. } = trashed;
+    let prediction_id = image.prediction_id.clone();
+    let crawl_key = format!("{}:{}", image.uploaded_by, name);
+
+    append_image_block(BTYPE_REGISTER_IMAGE, name, &image);
+    STABLE_IMAGES.with_borrow_mut(|images| images.insert(name.to_string(), image));
+
+    let has_crawl_result = crawl_result.is_some();
+    if let Some(result) = crawl_result {
+        index_crawl_domains(&crawl_key, &result);
+        STABLE_CRAWL_RESULTS.with_borrow_mut(|results| results.insert(crawl_key.clone(), result));
+    }
+    if let Some(history) = match_history {
+        STABLE_MATCH_HISTORY.with_borrow_mut(|map| map.insert(crawl_key.clone(), history));
+    }
+
+    for SubjectHash { subject_id, image_hash, registration } in &subject_hashes {
+        let entry = registration
+            .clone()
+            .unwrap_or_else(|| SubjectImageEntry::legacy(image_hash.clone()));
+        if link_subject_hash(subject_id, entry) {
+            record_subject_event(subject_id, SubjectAction::HashAdded, vec![image_hash.clone()]);
+        }
+    }
+
+    update_prediction_index(&prediction_id, |entry| {
+        push_unique(&mut entry.image_names, name);
+        if has_crawl_result {
+            push_unique(&mut entry.crawl_keys, &crawl_key);
+        }
+        for SubjectHash { image_hash, .. } in &subject_hashes {
+            push_unique(&mut entry.image_hashes, image_hash);
+        }
+    });
+}
+
+fn purge_expired_trash() {
+    purge_trash(time());
+}
+
+/// Drop trashed images whose `purge_at` is reached, along with the crawl result,
+/// match history and hashes they carry, and stop counting them against storage
+fn purge_trash(now: u64) {
+    let expired: Vec<(String, TrashedImage)> = STABLE_TRASH.with_borrow(|trash| {
+        trash
+            .iter()
+            .filter(|(_, trashed)| trashed.purge_at <= now)
+            .collect()
+    });
+
+    STABLE_TRASH.with_borrow_mut(|trash| {
+        for (name, trashed) in expired {
+            trash.remove(&name);
+            track_image_storage(&trashed.image, false);
+            ic_cdk::println!("Trashed image '{}' purged", name);
+        }
+    });
+}
+
+/// Delete an image by name, validating the user ID. The image's crawl result
+/// and subject hashes go with it; when a trash retention is configured they
+/// stay restorable until the retention period has passed.
+#[ic_cdk::update]
+fn delete_image(user_id: String, name: String) -> Result<(), SentinelError> {
+    AuditCall::begin("delete_image", Some(&user_id), format!("name: {}", name)).run(move || {
+        let image = STABLE_IMAGES.with_borrow(|images| images.get(&name));
+        let Some(image) = image else {
+            return Err(SentinelError::not_found(format!("Image '{}' not found.", name)));
+        };
+        if image.uploaded_by != user_id {
+            return Err(SentinelError::access_denied("Access denied: You do not own this image."));
+        }
+
+        let mut trashed = detach_image(&name, image);
+
+        match config().trash_retention_secs {
+            Some(retention) if retention > 0 => {
+                trashed.purge_at = trash_purge_at(trashed.deleted_at, retention);
+                STABLE_TRASH.with_borrow_mut(|trash| trash.insert(name.clone(), trashed));
+                ic_cdk::println!("Image '{}' moved to trash by user '{}'", name, user_id);
+            }
+            _ => {
+                track_image_storage(&trashed.image, false);
+                ic_cdk::println!("Image '{}' deleted successfully by user '{}'", name, user_id);
+            }
+        }
+
+        Ok(())
+    })
+}
+
+/// Restore a trashed image and the records deleted with it
+#[ic_cdk::update]
+fn restore_image(user_id: String, name: String) -> Result<(), SentinelError> {
+    AuditCall::begin("restore_image", Some(&user_id), format!("name: {}", name)).run(move || {
+        let trashed = STABLE_TRASH.with_borrow(|trash| trash.get(&name));
+        let Some(trashed) = trashed else {
+            return Err(SentinelError::not_found(format!("Image '{}' not found in trash.", name)));
+        };
+        if trashed.image.uploaded_by != user_id {
+            return Err(SentinelError::access_denied("Access denied: You do not own this image."));
+        }
+        if STABLE_IMAGES.with_borrow(|images| images.contains_key(&name)) {
+            return Err(SentinelError::already_exists(format!(
+                "An image with the name '{}' already exists.",
+                name
+            )));
+        }
+        if let Some(subject_id) = &trashed.image.subject_id {
+            ensure_active_consent(subject_id)?;
+        }
+        for SubjectHash { subject_id, .. } in &trashed.subject_hashes {
+            ensure_active_consent(subject_id)?;
+        }
+
+        STABLE_TRASH.with_borrow_mut(|trash| trash.remove(&name));
+        reattach_image(&name, trashed);
+        ic_cdk::println!("Image '{}' restored by user '{}'", name, user_id);
+        Ok(())
+    })
+}
+
+/// Permanently delete a trashed image without waiting for its restore window
+#[ic_cdk::update]
+fn purge_image(user_id: String, name: String) -> Result<(), SentinelError> {
+    AuditCall::begin("purge_image", Some(&user_id), format!("name: {}", name)).run(move || {
+        STABLE_TRASH.with_borrow_mut(|trash| {
+            if let Some(trashed) = trash.get(&name) {
+                if trashed.image.uploaded_by == user_id {
+                    trash.remove(&name);
+                    track_image_storage(&trashed.image, false);
+                    ic_cdk::println!("Trashed image '{}' purged by user '{}'", name, user_id);
+                    Ok(())
+                } else {
+                    Err(SentinelError::access_denied("Access denied: You do not own this image."))
+                }
+            } else {
+                Err(SentinelError::not_found(format!("Image '{}' not found in trash.", name)))
+            }
+        })
+    })
+}
+
+/// List the trashed images of a user with their restore deadlines
+#[ic_cdk::query]
+fn list_trashed_images(user_id: String) -> Vec<TrashedImageSummary> {
+    STABLE_TRASH.with_borrow(|trash| {
+        trash
+            .iter()
+            .filter(|(_, trashed)| trashed.image.uploaded_by == user_id)
+            .map(|(name, trashed)| TrashedImageSummary {
+                name,
+                prediction_id: trashed.image.prediction_id,
+                deleted_at: trashed.deleted_at,
+                purge_at: trashed.purge_at,
+            })
+            .collect()
+    })
+}
+
+/// Hand an image to a new owner, moving its crawl result and match history along with it
+fn transfer_image_owner(name: &str, to_user_id: &str) {
+    let Some(mut image) = STABLE_IMAGES.with_borrow(|images| images.get(&name.to_string())) else {
+        return;
+    };
+    track_image_storage(&image, false);
+    let from_user_id = std::mem::replace(&mut image.uploaded_by, to_user_id.to_string());
+    let prediction_id = image.prediction_id.clone();
+    track_image_storage(&image, true);
+    STABLE_IMAGES.with_borrow_mut(|images| images.insert(name.to_string(), image));
+
+    let old_key = format!("{}:{}", from_user_id, name);
+    let new_key = format!("{}:{}", to_user_id, name);
+    let crawl_result = STABLE_CRAWL_RESULTS.with_borrow_mut(|results| {
+        let result = results.remove(&old_key)?;
+        results.insert(new_key.clone(), result.clone());
+        Some(result)
+    });
+    if let Some(result) = &crawl_result {
+        unindex_crawl_domains(&old_key, result);
+        index_crawl_domains(&new_key, result);
+    }
+    STABLE_MATCH_HISTORY.with_borrow_mut(|histories| {
+        if let Some(history) = histories.remove(&old_key) {
+            histories.insert(new_key.clone(), history);
+        }
+    });
+    if crawl_result.is_some() {
+        update_prediction_index(&prediction_id, |entry| {
+            entry.crawl_keys.retain(|key| key != &old_key);
+            push_unique(&mut entry.crawl_keys, &new_key);
+        });
+    }
+
+    append_block(
+        BTYPE_TRANSFER_IMAGE,
+        vec![
+            ("name".to_string(), text(name)),
+            ("from".to_string(), text(&from_user_id)),
+            ("to".to_string(), text(to_user_id)),
+            ("prediction_id".to_string(), text(&prediction_id)),
+            ("caller".to_string(), text(&ic_cdk::caller().to_text())),
+        ],
+    );
+    ic_cdk::println!("Image '{}' transferred from user '{}' to user '{}'", name, from_user_id, to_user_id);
+}
+
+fn pending_offer(offer_id: u64) -> Result<TransferOffer, SentinelError> {
+    match STABLE_TRANSFER_OFFERS.with_borrow(|offers| offers.get(&offer_id)) {
+        Some(offer) if offer.status == TransferStatus::Pending => Ok(offer),
+        Some(_) => Err(SentinelError::failed_precondition(format!(
+            "Transfer offer {} is no longer pending.",
+            offer_id
+        ))),
+        None => Err(SentinelError::not_found(format!("Transfer offer {} not found.", offer_id))),
+    }
+}
+
+fn resolve_offer(mut offer: TransferOffer, status: TransferStatus) {
+    offer.status = status;
+    offer.resolved_at = Some(time());
+    STABLE_TRANSFER_OFFERS.with_borrow_mut(|offers| offers.insert(offer.id, offer));
+}
+
+/// Offer an image of `from_user_id`, or all images of one subject, to another
+/// user. Nothing changes hands until the recipient accepts the offer.
+#[ic_cdk::update]
+fn offer_transfer(from_user_id: String, scope: ContentScope, to_user_id: String) -> Result<u64, SentinelError> {
+    AuditCall::begin(
+        "offer_transfer",
+        Some(&from_user_id),
+        format!("to_user_id: {}, scope: {:?}", to_user_id, scope),
+    )
+    .run(move || {
+        if from_user_id.is_empty() {
+            return Err(SentinelError::invalid("from_user_id", "User ID cannot be empty."));
+        }
+        ensure_caller_acts_for(&from_user_id)?;
+        if to_user_id.is_empty() {
+            return Err(SentinelError::invalid("to_user_id", "User ID cannot be empty."));
+        }
+        if from_user_id == to_user_id {
+            return Err(SentinelError::invalid(
+                "to_user_id",
+                "Cannot transfer content to the same user.",
+            ));
+        }
 
-    let mut user_results = HashMap::new();
+        ensure_supported_scope(&scope)?;
+        match &scope {
+            ContentScope::Image(name) => {
+                let image = STABLE_IMAGES.with_borrow(|images| images.get(name));
+                match image {
+                    Some(image) if image.uploaded_by == from_user_id => {}
+                    Some(_) => return Err(SentinelError::access_denied("Access denied: You do not own this image.")),
+                    None => return Err(SentinelError::not_found(format!("Image '{}' not found.", name))),
+                }
+            }
+            ContentScope::Subject(subject_id) => {
+                if subject_image_names(&from_user_id, subject_id).is_empty() {
+                    return Err(SentinelError::not_found(format!(
+                        "No images of subject '{}' found for this user.",
+                        subject_id
+                    )));
+                }
+                ensure_no_trashed_subject_images(&from_user_id, subject_id)?;
+            }
+            ContentScope::Portfolio => {}
+        }
+
+        STABLE_TRANSFER_OFFERS.with_borrow_mut(|offers| {
+            let id = offers.last_key_value().map_or(0, |(id, _)| id + 1);
+            offers.insert(
+                id,
+                TransferOffer {
+                    id,
+                    from_user_id: from_user_id.clone(),
+                    to_user_id: to_user_id.clone(),
+                    scope,
+                    offered_by: ic_cdk::caller().to_text(),
+                    created_at: time(),
+                    status: TransferStatus::Pending,
+                    resolved_at: None,
+                },
+            );
+            ic_cdk::println!("Transfer offer {} from '{}' to '{}' created", id, from_user_id, to_user_id);
+            Ok(id)
+        })
+    })
+}
+
+/// Accept a pending transfer offer addressed to `user_id`
+#[ic_cdk::update]
+fn accept_transfer(user_id: String, offer_id: u64) -> Result<(), SentinelError> {
+    AuditCall::begin("accept_transfer", Some(&user_id), format!("offer_id: {}", offer_id)).run(move || {
+        ensure_caller_acts_for(&user_id)?;
+        let offer = pending_offer(offer_id)?;
+        if offer.to_user_id != user_id {
+            return Err(SentinelError::access_denied(
+                "Access denied: This transfer offer is addressed to another user.",
+            ));
+        }
+
+        let names = match &offer.scope {
+            ContentScope::Image(name) => {
+                let image = STABLE_IMAGES.with_borrow(|images| images.get(name));
+                match image {
+                    Some(image) if image.uploaded_by == offer.from_user_id => vec![name.clone()],
+                    _ => {
+                        return Err(SentinelError::failed_precondition(format!(
+                            "Image '{}' is no longer owned by the sender.",
+                            name
+                        )))
+                    }
+                }
+            }
+            ContentScope::Subject(subject_id) => {
+                ensure_no_trashed_subject_images(&offer.from_user_id, subject_id)?;
+                subject_image_names(&offer.from_user_id, subject_id)
+            }
+            ContentScope::Portfolio => Vec::new(),
+        };
+        let bytes = STABLE_IMAGES.with_borrow(|images| {
+            names
+                .iter()
+                .filter_map(|name| images.get(name))
+                .map(|image| image.content.len() as u64)
+                .sum()
+        });
+        ensure_storage_quota(&user_id, names.len() as u64, bytes)?;
+
+        for name in &names {
+            transfer_image_owner(name, &user_id);
+        }
+        resolve_offer(offer, TransferStatus::Accepted);
+        Ok(())
+    })
+}
+
+/// Withdraw (sender) or decline (recipient) a pending transfer offer
+#[ic_cdk::update]
+fn cancel_transfer(user_id: String, offer_id: u64) -> Result<(), SentinelError> {
+    AuditCall::begin("cancel_transfer", Some(&user_id), format!("offer_id: {}", offer_id)).run(move || {
+        ensure_caller_acts_for(&user_id)?;
+        let offer = pending_offer(offer_id)?;
+        if offer.from_user_id != user_id && offer.to_user_id != user_id {
+            return Err(SentinelError::access_denied(
+                "Access denied: You are not part of this transfer offer.",
+            ));
+        }
+
+        resolve_offer(offer, TransferStatus::Cancelled);
+        Ok(())
+    })
+}
+
+/// Create an organization with `user_id` as its first admin
+#[ic_cdk::update]
+fn create_organization(user_id: String, org_id: String, name: String) -> Result<(), SentinelError> {
+    AuditCall::begin(
+        "create_organization",
+        Some(&user_id),
+        format!("org_id: {}, name: {}", org_id, name),
+    )
+    .run(move || {
+        if user_id.is_empty() {
+            return Err(SentinelError::invalid("user_id", "User ID cannot be empty."));
+        }
+        ensure_caller_acts_for(&user_id)?;
+        if org_id.is_empty() {
+            return Err(SentinelError::invalid("org_id", "Organization ID cannot be empty."));
+        }
+        if STABLE_ORGANIZATIONS.with_borrow(|orgs| orgs.contains_key(&org_id)) {
+            return Err(SentinelError::already_exists(format!(
+                "An organization with the ID '{}' already exists.",
+                org_id
+            )));
+        }
+
+        let now = time();
+        save_org(Organization {
+            org_id: org_id.clone(),
+            name,
+            admins: vec![user_id.clone()],
+            members: Vec::new(),
+            models: Vec::new(),
+            invited_models: Vec::new(),
+            crawl_budget: None,
+            crawls_used: 0,
+            budget_period_start: now,
+            created_by: ic_cdk::caller().to_text(),
+            created_at: now,
+        });
+        ic_cdk::println!("Organization '{}' created by user '{}'", org_id, user_id);
+        Ok(())
+    })
+}
+
+/// Add a staff member to an organization or change their role; admins only
+#[ic_cdk::update]
+fn set_org_member(user_id: String, org_id: String, member_user_id: String, role: OrgRole) -> Result<(), SentinelError> {
+    AuditCall::begin(
+        "set_org_member",
+        Some(&user_id),
+        format!("org_id: {}, member_user_id: {}, admin: {}", org_id, member_user_id, role == OrgRole::Admin),
+    )
+    .run(move || {
+        if member_user_id.is_empty() {
+            return Err(SentinelError::invalid("member_user_id", "User ID cannot be empty."));
+        }
+        ensure_caller_acts_for(&user_id)?;
+        let mut org = org_for(&user_id, &org_id, OrgRole::Admin)?;
+
+        org.admins.retain(|admin| admin != &member_user_id);
+        org.members.retain(|member| member != &member_user_id);
+        match role {
+            OrgRole::Admin => org.admins.push(member_user_id),
+            OrgRole::Member => org.members.push(member_user_id),
+        }
+        if org.admins.is_empty() {
+            return Err(SentinelError::failed_precondition(
+                "An organization needs at least one admin.",
+            ));
+        }
+        save_org(org);
+        Ok(())
+    })
+}
+
+/// Remove a staff member or model from an organization; admins only, or a
+/// member or model removing themselves
+#[ic_cdk::update]
+fn remove_org_member(user_id: String, org_id: String, member_user_id: String) -> Result<(), SentinelError> {
+    AuditCall::begin(
+        "remove_org_member",
+        Some(&user_id),
+        format!("org_id: {}, member_user_id: {}", org_id, member_user_id),
+    )
+    .run(move || {
+        ensure_caller_acts_for(&user_id)?;
+        let mut org = if user_id == member_user_id {
+            STABLE_ORGANIZATIONS
+                .with_borrow(|orgs| orgs.get(&org_id))
+                .ok_or_else(|| SentinelError::not_found(format!("Organization '{}' not found.", org_id)))?
+        } else {
+            org_for(&user_id, &org_id, OrgRole::Admin)?
+        };
+
+        if org.role_of(&member_user_id).is_none()
+            && !org.models.contains(&member_user_id)
+            && !org.invited_models.contains(&member_user_id)
+        {
+            return Err(SentinelError::not_found(format!(
+                "User '{}' is not part of this organization.",
+                member_user_id
+            )));
+        }
+
+        org.admins.retain(|admin| admin != &member_user_id);
+        org.members.retain(|member| member != &member_user_id);
+        org.models.retain(|model| model != &member_user_id);
+        org.invited_models.retain(|model| model != &member_user_id);
+        if org.admins.is_empty() {
+            return Err(SentinelError::failed_precondition(
+                "An organization needs at least one admin.",
+            ));
+        }
+        save_org(org);
+        Ok(())
+    })
+}
+
+/// Invite a model to be managed by an organization; admins only
+#[ic_cdk::update]
+fn invite_org_model(user_id: String, org_id: String, model_user_id: String) -> Result<(), SentinelError> {
+    AuditCall::begin(
+        "invite_org_model",
+        Some(&user_id),
+        format!("org_id: {}, model_user_id: {}", org_id, model_user_id),
+    )
+    .run(move || {
+        if model_user_id.is_empty() {
+            return Err(SentinelError::invalid("model_user_id", "User ID cannot be empty."));
+        }
+        ensure_caller_acts_for(&user_id)?;
+        let mut org = org_for(&user_id, &org_id, OrgRole::Admin)?;
+        if org.models.contains(&model_user_id) {
+            return Err(SentinelError::already_exists(format!(
+                "Model '{}' is already part of this organization.",
+                model_user_id
+            )));
+        }
+
+        push_unique(&mut org.invited_models, &model_user_id);
+        save_org(org);
+        Ok(())
+    })
+}
+
+/// Accept an organization's invitation, giving its staff access to the model's content
+#[ic_cdk::update]
+fn accept_org_invite(model_user_id: String, org_id: String) -> Result<(), SentinelError> {
+    AuditCall::begin("accept_org_invite", Some(&model_user_id), format!("org_id: {}", org_id)).run(move || {
+        ensure_caller_acts_for(&model_user_id)?;
+        let Some(mut org) = STABLE_ORGANIZATIONS.with_borrow(|orgs| orgs.get(&org_id)) else {
+            return Err(SentinelError::not_found(format!("Organization '{}' not found.", org_id)));
+        };
+        if !org.invited_models.contains(&model_user_id) {
+            return Err(SentinelError::failed_precondition(format!(
+                "Model '{}' has not been invited to this organization.",
+                model_user_id
+            )));
+        }
+        if let Some(current) = model_organization(&model_user_id) {
+            return Err(SentinelError::failed_precondition(format!(
+                "Model '{}' already belongs to organization '{}'.",
+                model_user_id, current.org_id
+            )));
+        }
+
+        org.invited_models.retain(|model| model != &model_user_id);
+        org.models.push(model_user_id);
+        save_org(org);
+        Ok(())
+    })
+}
+
+/// Set the number of crawls an organization may run per period; `None` removes the limit
+#[ic_cdk::update]
+fn set_org_crawl_budget(user_id: String, org_id: String, crawl_budget: Option<u64>) -> Result<(), SentinelError> {
+    AuditCall::begin(
+        "set_org_crawl_budget",
+        Some(&user_id),
+        format!("org_id: {}, crawl_budget: {:?}", org_id, crawl_budget),
+    )
+    .run(move || {
+        ensure_caller_acts_for(&user_id)?;
+        let mut org = org_for(&user_id, &org_id, OrgRole::Admin)?;
+        org.crawl_budget = crawl_budget;
+        save_org(org);
+        Ok(())
+    })
+}
+
+#[ic_cdk::query]
+fn get_organization(user_id: String, org_id: String) -> Result<Organization, SentinelError> {
+    let mut org = org_for(&user_id, &org_id, OrgRole::Member)?;
+    org.roll_budget_period(time());
+    Ok(org)
+}
+
+/// Images of every model in an organization
+#[ic_cdk::query]
+fn list_org_images(user_id: String, org_id: String) -> Result<Vec<ModelImages>, SentinelError> {
+    let org = org_for(&user_id, &org_id, OrgRole::Member)?;
+
+    Ok(org
+        .models
+        .into_iter()
+        .map(|model_user_id| ModelImages {
+            image_names: list_images(model_user_id.clone()),
+            model_user_id,
+        })
+        .collect())
+}
+
+/// Crawl results of every model in an organization as JSON, keyed by model and image name
+#[ic_cdk::query]
+fn get_org_crawl_results(user_id: String, org_id: String) -> Result<String, SentinelError> {
+    let org = org_for(&user_id, &org_id, OrgRole::Member)?;
+
+    let mut org_results: HashMap<String, HashMap<String, CrawlResult>> = HashMap::new();
     STABLE_CRAWL_RESULTS.with_borrow(|results| {
-        for (key, value) in results.iter() {
-            ic_cdk::println!("Stored result key '{}'", key);
-            ic_cdk::println!("Prefix '{}'", prefix);
-            if key.starts_with(&prefix) {
-                if let Some(image_name) = key.strip_prefix(&prefix) {
-                    ic_cdk::println!("Image name '{}'", image_name);
+        for model_user_id in &org.models {
+            let prefix = format!("{}:", model_user_id);
+            let model_results: HashMap<String, CrawlResult> = results
+                .range(prefix.clone()..)
+                .map_while(|(key, value)| key.strip_prefix(&prefix).map(|name| (name.to_string(), value)))
+                .collect();
+            org_results.insert(model_user_id.clone(), model_results);
+        }
+    });
+
+    serde_json::to_string(&org_results).map_err(|err| {
+        ic_cdk::println!("Failed to serialize results: {}", err);
+        SentinelError::internal("Failed to serialize results.")
+    })
+}
+
+/// Give another user access to an image of `owner_user_id` or to all images of one subject
+#[ic_cdk::update]
+fn grant_access(
+    owner_user_id: String,
+    grantee: String,
+    scope: ContentScope,
+    permissions: Vec<Permission>,
+    expires_at: Option<u64>,
+) -> Result<u64, SentinelError> {
+    AuditCall::begin(
+        "grant_access",
+        Some(&owner_user_id),
+        format!(
+            "grantee: {}, scope: {:?}, permissions: {:?}, expires_at: {:?}",
+            grantee, scope, permissions, expires_at
+        ),
+    )
+    .run(move || {
+        if owner_user_id.is_empty() {
+            return Err(SentinelError::invalid("owner_user_id", "User ID cannot be empty."));
+        }
+        if grantee.is_empty() {
+            return Err(SentinelError::invalid("grantee", "User ID cannot be empty."));
+        }
+        ensure_caller_acts_for(&owner_user_id)?;
+        if owner_user_id == grantee {
+            return Err(SentinelError::invalid("grantee", "Cannot grant access to yourself."));
+        }
+        if permissions.is_empty() {
+            return Err(SentinelError::invalid("permissions", "Permissions cannot be empty."));
+        }
+        let now = time();
+        if expires_at.is_some_and(|expires_at| expires_at <= now) {
+            return Err(SentinelError::invalid("expires_at", "Grant expiry must be in the future."));
+        }
+        ensure_supported_scope(&scope)?;
+        if let ContentScope::Image(name) = &scope {
+            let image = STABLE_IMAGES.with_borrow(|images| images.get(name));
+            match image {
+                Some(image) if image.uploaded_by == owner_user_id => {}
+                Some(_) => return Err(SentinelError::access_denied("Access denied: You do not own this image.")),
+                None => return Err(SentinelError::not_found(format!("Image '{}' not found.", name))),
+            }
+        }
+
+        STABLE_ACCESS_GRANTS.with_borrow_mut(|grants| {
+            let id = grants.last_key_value().map_or(0, |(id, _)| id + 1);
+            grants.insert(
+                id,
+                AccessGrant {
+                    id,
+                    owner_user_id: owner_user_id.clone(),
+                    grantee: grantee.clone(),
+                    scope,
+                    permissions,
+                    granted_by: ic_cdk::caller().to_text(),
+                    created_at: now,
+                    expires_at,
+                    revoked_at: None,
+                },
+            );
+            ic_cdk::println!("Access grant {} from '{}' to '{}' created", id, owner_user_id, grantee);
+            Ok(id)
+        })
+    })
+}
+
+/// Revoke an access grant given by `owner_user_id`
+#[ic_cdk::update]
+fn revoke_access(owner_user_id: String, grant_id: u64) -> Result<(), SentinelError> {
+    AuditCall::begin("revoke_access", Some(&owner_user_id), format!("grant_id: {}", grant_id)).run(move || {
+        ensure_caller_acts_for(&owner_user_id)?;
+        STABLE_ACCESS_GRANTS.with_borrow_mut(|grants| {
+            let Some(mut grant) = grants.get(&grant_id) else {
+                return Err(SentinelError::not_found(format!(
+                    "Access grant {} not found.",
+                    grant_id
+                )));
+            };
+            if grant.owner_user_id != owner_user_id {
+                return Err(SentinelError::access_denied(
+                    "Access denied: You did not give this grant.",
+                ));
+            }
+            if grant.revoked_at.is_some() {
+                return Err(SentinelError::failed_precondition(format!(
+                    "Access grant {} has already been revoked.",
+                    grant_id
+                )));
+            }
+
+            grant.revoked_at = Some(time());
+            grants.insert(grant_id, grant);
+            ic_cdk::println!("Access grant {} revoked by '{}'", grant_id, owner_user_id);
+            Ok(())
+        })
+    })
+}
+
+/// Access grants given or held by a user, including expired and revoked ones
+#[ic_cdk::query]
+fn list_access_grants(user_id: String) -> Vec<AccessGrant> {
+    STABLE_ACCESS_GRANTS.with_borrow(|grants| {
+        grants
+            .values()
+            .filter(|grant| grant.owner_user_id == user_id || grant.grantee == user_id)
+            .collect()
+    })
+}
+
+/// Transfer offers sent or received by a user, newest first
+#[ic_cdk::query]
+fn list_transfer_offers(user_id: String) -> Vec<TransferOffer> {
+    STABLE_TRANSFER_OFFERS.with_borrow(|offers| {
+        offers
+            .iter()
+            .rev()
+            .filter(|(_, offer)| offer.from_user_id == user_id || offer.to_user_id == user_id)
+            .map(|(_, offer)| offer)
+            .collect()
+    })
+}
+
+/// Link a user or subject ID to the principal that may act for it, or unlink
+/// it with `None`; controllers only. Endpoints acting for a user, such as
+/// transfers, accept calls from the linked principal or a controller.
+#[ic_cdk::update]
+fn link_principal(id: String, principal: Option<Principal>) -> Result<(), SentinelError> {
+    AuditCall::begin("link_principal", Some(&id), format!("principal: {:?}", principal)).run(move || {
+        ensure_controller()?;
+        if id.is_empty() {
+            return Err(SentinelError::invalid("id", "ID cannot be empty."));
+        }
+        STABLE_PRINCIPALS.with_borrow_mut(|principals| match principal {
+            Some(principal) => principals.insert(id, principal),
+            None => principals.remove(&id),
+        });
+        Ok(())
+    })
+}
+
+#[ic_cdk::query]
+fn get_linked_principal(id: String) -> Option<Principal> {
+    STABLE_PRINCIPALS.with_borrow(|principals| principals.get(&id))
+}
+
+/// Longest trash retention `set_trash_retention` accepts, ten years
+const MAX_TRASH_RETENTION_SECS: u64 = 10 * 365 * 24 * 60 * 60;
+
+/// When an image deleted at `deleted_at` leaves the trash. A retention too long
+/// to represent keeps the image until it is purged by hand.
+fn trash_purge_at(deleted_at: u64, retention_secs: u64) -> u64 {
+    retention_secs
+        .checked_mul(NANOS_PER_SEC)
+        .and_then(|retention| deleted_at.checked_add(retention))
+        .unwrap_or(u64::MAX)
+}
+
+/// Set how long deleted images stay restorable; `0` deletes them immediately
+#[ic_cdk::update]
+fn set_trash_retention(retention_secs: u64) -> Result<(), SentinelError> {
+    AuditCall::begin(
+        "set_trash_retention",
+        None,
+        format!("retention_secs: {}", retention_secs),
+    )
+    .run(move || {
+        if retention_secs > MAX_TRASH_RETENTION_SECS {
+            return Err(SentinelError::invalid("retention_secs", format!(
+                "Trash retention can be at most {} seconds.",
+                MAX_TRASH_RETENTION_SECS
+            )));
+        }
+        update_config(|config| config.trash_retention_secs = Some(retention_secs))
+    })
+}
+
+#[ic_cdk::query]
+fn get_config() -> Config {
+    let mut config = config();
+    // Provider API keys are secrets; only show whether one is set
+    for endpoint in config.provider_endpoints.iter_mut().flatten() {
+        if endpoint.api_key.is_some() {
+            endpoint.api_key = Some("<redacted>".to_string());
+        }
+    }
+    config
+}
+
+/// Set the quota for users without a tier or their own quota
+#[ic_cdk::update]
+fn set_default_quota(quota: Option<Quota>) -> Result<(), SentinelError> {
+    AuditCall::begin("set_default_quota", None, format!("quota: {:?}", quota)).run(move || {
+        update_config(|config| config.default_quota = quota)
+    })
+}
+
+/// Define the quota of a tier; `None` removes the tier
+#[ic_cdk::update]
+fn set_tier_quota(tier: String, quota: Option<Quota>) -> Result<(), SentinelError> {
+    AuditCall::begin(
+        "set_tier_quota",
+        None,
+        format!("tier: {}, quota: {:?}", tier, quota),
+    )
+    .run(move || {
+        if tier.is_empty() {
+            return Err(SentinelError::invalid("tier", "Tier cannot be empty."));
+        }
+        update_config(|config| {
+            let mut tier_quotas = config.tier_quotas.take().unwrap_or_default();
+            tier_quotas.retain(|tier_quota| tier_quota.tier != tier);
+            if let Some(quota) = quota {
+                tier_quotas.push(TierQuota { tier, quota });
+            }
+            config.tier_quotas = Some(tier_quotas);
+        })
+    })
+}
+
+/// Assign a user to a tier; `None` puts them back on the default quota
+#[ic_cdk::update]
+fn set_user_tier(user_id: String, tier: Option<String>) -> Result<(), SentinelError> {
+    AuditCall::begin("set_user_tier", Some(&user_id), format!("tier: {:?}", tier)).run(move || {
+        ensure_controller()?;
+        if user_id.is_empty() {
+            return Err(SentinelError::invalid("user_id", "User ID cannot be empty."));
+        }
+        update_user_account(&user_id, |account| account.tier = tier);
+        Ok(())
+    })
+}
+
+/// Give a user their own quota, overriding their tier; `None` removes the override
+#[ic_cdk::update]
+fn set_user_quota(user_id: String, quota: Option<Quota>) -> Result<(), SentinelError> {
+    AuditCall::begin("set_user_quota", Some(&user_id), format!("quota: {:?}", quota)).run(move || {
+        ensure_controller()?;
+        if user_id.is_empty() {
+            return Err(SentinelError::invalid("user_id", "User ID cannot be empty."));
+        }
+        update_user_account(&user_id, |account| account.quota = quota);
+        Ok(())
+    })
+}
+
+/// Set the largest crawl response accepted, which bounds the cycles attached to each outcall
+#[ic_cdk::update]
+fn set_max_response_bytes(max_response_bytes: u64) -> Result<(), SentinelError> {
+    AuditCall::begin(
+        "set_max_response_bytes",
+        None,
+        format!("max_response_bytes: {}", max_response_bytes),
+    )
+    .run(move || {
+        if max_response_bytes == 0 || max_response_bytes > MAX_RESPONSE_BYTES_LIMIT {
+            return Err(SentinelError::invalid("max_response_bytes", format!(
+                "Max response bytes must be between 1 and {}.",
+                MAX_RESPONSE_BYTES_LIMIT
+            )));
+        }
+        update_config(|config| config.max_response_bytes = Some(max_response_bytes))
+    })
+}
+
+/// Set the URL and API key a detection provider is queried with
+#[ic_cdk::update]
+fn set_provider_endpoint(
+    provider: DetectionProvider,
+    url: String,
+    api_key: Option<String>,
+) -> Result<(), SentinelError> {
+    AuditCall::begin(
+        "set_provider_endpoint",
+        None,
+        format!("provider: {:?}, url: {}, api_key: {}", provider, url, api_key.is_some()),
+    )
+    .run(move || {
+        if !url.starts_with("https://") {
+            return Err(SentinelError::invalid("url", "Provider URL must use https."));
+        }
+        update_config(|config| {
+            let endpoints = config.provider_endpoints.get_or_insert_with(Vec::new);
+            endpoints.retain(|endpoint| endpoint.provider != provider);
+            endpoints.push(ProviderEndpoint { provider, url, api_key });
+        })
+    })
+}
+
+/// Set the node count of the canister's subnet used to price outcalls
+#[ic_cdk::update]
+fn set_subnet_size(subnet_size: u64) -> Result<(), SentinelError> {
+    AuditCall::begin("set_subnet_size", None, format!("subnet_size: {}", subnet_size)).run(move || {
+        if subnet_size == 0 || subnet_size > MAX_SUBNET_SIZE {
+            return Err(SentinelError::invalid("subnet_size", format!(
+                "Subnet size must be between 1 and {}.",
+                MAX_SUBNET_SIZE
+            )));
+        }
+        update_config(|config| config.subnet_size = Some(subnet_size))
+    })
+}
+
+/// Accept payments for crawl credits from an ICRC-2 ledger; `None` makes crawls free again
+#[ic_cdk::update]
+fn set_payment_ledger(ledger: Option<Principal>, price_per_credit: u64) -> Result<(), SentinelError> {
+    AuditCall::begin(
+        "set_payment_ledger",
+        None,
+        format!("ledger: {:?}, price_per_credit: {}", ledger.map(|l| l.to_text()), price_per_credit),
+    )
+    .run(move || {
+        if ledger.is_some() && price_per_credit == 0 {
+            return Err(SentinelError::invalid(
+                "price_per_credit",
+                "Price per credit must be greater than zero.",
+            ));
+        }
+        update_config(|config| {
+            config.payment_ledger = ledger;
+            config.price_per_credit = Some(price_per_credit);
+        })
+    })
+}
+
+/// Buy crawl credits for `user_id`. The caller must first `icrc2_approve` this
+/// canister on the payment ledger for the price of the credits plus the fee.
+#[ic_cdk::update]
+async fn buy_crawl_credits(user_id: String, credits: u64) -> Result<u64, SentinelError> {
+    let audit = AuditCall::begin("buy_crawl_credits", Some(&user_id), format!("credits: {}", credits));
+    audit.finish(pay_for_credits(user_id, credits).await)
+}
+
+async fn pay_for_credits(user_id: String, credits: u64) -> Result<u64, SentinelError> {
+    if user_id.is_empty() {
+        return Err(SentinelError::invalid("user_id", "User ID cannot be empty."));
+    }
+    if credits == 0 {
+        return Err(SentinelError::invalid("credits", "Credits must be greater than zero."));
+    }
+    let config = config();
+    let Some(ledger) = config.payment_ledger else {
+        return Err(SentinelError::failed_precondition("Payments are not enabled."));
+    };
+    let amount = config
+        .price_per_credit
+        .unwrap_or(0)
+        .checked_mul(credits)
+        .ok_or_else(|| SentinelError::invalid("credits", "Too many credits requested."))?;
+
+    let payer = ic_cdk::caller();
+    let args = TransferFromArgs {
+        spender_subaccount: None,
+        from: Account {
+            owner: payer,
+            subaccount: None,
+        },
+        to: Account {
+            owner: ic_cdk::id(),
+            subaccount: None,
+        },
+        amount: Nat::from(amount),
+        fee: None,
+        memo: None,
+        created_at_time: Some(time()),
+    };
+    let (result,): (Result<Nat, TransferFromError>,) =
+        ic_cdk::call(ledger, "icrc2_transfer_from", (args,))
+            .await
+            .map_err(|(code, message)| {
+                SentinelError::ledger_error(format!(
+                    "Ledger call failed. RejectionCode: {code:?}, Error: {message}"
+                ))
+            })?;
+    let block_index = result.map_err(|err| match err {
+        TransferFromError::InsufficientFunds { .. } | TransferFromError::InsufficientAllowance { .. } => {
+            SentinelError::insufficient_funds(format!("Payment failed: {:?}", err))
+        }
+        _ => SentinelError::ledger_error(format!("Payment failed: {:?}", err)),
+    })?;
+
+    let balance = crawl_credits(&user_id) + credits;
+    set_crawl_credits(&user_id, balance);
+    STABLE_PAYMENTS.with_borrow_mut(|payments| {
+        let id = payments.last_key_value().map_or(0, |(id, _)| id + 1);
+        payments.insert(
+            id,
+            Payment {
+                id,
+                user_id: user_id.clone(),
+                payer,
+                ledger,
+                amount,
+                credits,
+                block_index,
+                timestamp: time(),
+            },
+        );
+    });
+    ic_cdk::println!("User '{}' bought {} crawl credits", user_id, credits);
+    Ok(balance)
+}
+
+#[ic_cdk::query]
+fn get_crawl_credits(user_id: String) -> u64 {
+    crawl_credits(&user_id)
+}
+
+#[ic_cdk::query]
+fn list_payments(user_id: String) -> Vec<Payment> {
+    STABLE_PAYMENTS.with_borrow(|payments| {
+        payments
+            .values()
+            .filter(|payment| payment.user_id == user_id)
+            .collect()
+    })
+}
+
+/// Refuse crawls whose estimated cost is not covered by the caller's cycle balance
+#[ic_cdk::update]
+fn set_require_prepaid_cycles(required: bool) -> Result<(), SentinelError> {
+    AuditCall::begin("set_require_prepaid_cycles", None, format!("required: {}", required)).run(move || {
+        update_config(|config| config.require_prepaid_cycles = Some(required))
+    })
+}
+
+/// Credit the cycles attached to this call to a user's balance
+#[ic_cdk::update]
+fn deposit_cycles(user_id: String) -> Result<u64, SentinelError> {
+    AuditCall::begin(
+        "deposit_cycles",
+        Some(&user_id),
+        format!("cycles: {}", msg_cycles_available128()),
+    )
+    .run(move || {
+        if user_id.is_empty() {
+            return Err(SentinelError::invalid("user_id", "User ID cannot be empty."));
+        }
+        let available = u64::try_from(msg_cycles_available128()).unwrap_or(u64::MAX);
+        if available == 0 {
+            return Err(SentinelError::failed_precondition("No cycles attached to the call."));
+        }
+
+        let accepted = msg_cycles_accept128(available as u128) as u64;
+        Ok(book_cycles(&user_id, CyclesEntryKind::Deposit, accepted, None))
+    })
+}
+
+/// Credit cycles paid for outside the canister to a user's balance
+#[ic_cdk::update]
+fn grant_cycles(user_id: String, cycles: u64) -> Result<u64, SentinelError> {
+    AuditCall::begin("grant_cycles", Some(&user_id), format!("cycles: {}", cycles)).run(move || {
+        ensure_controller()?;
+        if user_id.is_empty() {
+            return Err(SentinelError::invalid("user_id", "User ID cannot be empty."));
+        }
+        Ok(book_cycles(&user_id, CyclesEntryKind::Grant, cycles, None))
+    })
+}
+
+#[ic_cdk::query]
+fn get_cycles_account(user_id: String) -> CyclesAccount {
+    cycles_account(&user_id)
+}
+
+/// All deposits, grants and outcall charges of a user, oldest first
+#[ic_cdk::query]
+fn get_cycles_ledger(user_id: String) -> Vec<CyclesLedgerEntry> {
+    STABLE_CYCLES_LEDGER.with_borrow(|ledger| {
+        ledger
+            .values()
+            .filter(|entry| entry.user_id == user_id)
+            .collect()
+    })
+}
 
-                    user_results.insert(image_name.to_string(), value.clone());
+/// Cycles a user has spent crawling each image
+#[ic_cdk::query]
+fn get_cycles_report(user_id: String) -> Vec<ImageCycles> {
+    let mut report: Vec<ImageCycles> = Vec::new();
+    STABLE_CYCLES_LEDGER.with_borrow(|ledger| {
+        for entry in ledger.values().filter(|entry| entry.user_id == user_id) {
+            let (CyclesEntryKind::Outcall { image_name } | CyclesEntryKind::OutcallRefund { image_name }) =
+                entry.kind.clone()
+            else {
+                continue;
+            };
+            let image = match report.iter().position(|image| image.image_name == image_name) {
+                Some(position) => &mut report[position],
+                None => {
+                    report.push(ImageCycles {
+                        image_name,
+                        outcalls: 0,
+                        cycles_attached: 0,
+                        cycles_refunded: 0,
+                        cycles_spent: 0,
+                    });
+                    report.last_mut().unwrap()
                 }
+            };
+            if let CyclesEntryKind::OutcallRefund { .. } = entry.kind {
+                image.cycles_refunded += entry.cycles;
+                image.cycles_spent = image.cycles_spent.saturating_sub(entry.cycles);
+                continue;
             }
+            // Entries booked before reservations hold the net cycles used
+            let attached = entry.cycles_attached.unwrap_or(entry.cycles);
+            image.outcalls += 1;
+            image.cycles_attached += attached;
+            image.cycles_refunded += attached.saturating_sub(entry.cycles);
+            image.cycles_spent += entry.cycles;
         }
     });
+    report
+}
 
-    if user_results.is_empty() {
-        Err("No crawl results found for this user.".to_string())
+/// Current storage and crawl consumption of a user together with their quota
+#[ic_cdk::query]
+fn get_usage(user_id: String) -> Usage {
+    usage(&user_id)
+}
+
+/// Details of a prediction, limited to the images and crawl results `user_id`
+/// may read. `None` when the prediction is unknown or none of it is readable.
+fn prediction_details(user_id: &str, prediction_id: &str) -> Option<PredictionDetails> {
+    let entry = STABLE_PREDICTION_INDEX.with_borrow(|index| index.get(&prediction_id.to_string()))?;
+
+    let image = entry.image_names.iter().find_map(|name| {
+        STABLE_IMAGES
+            .with_borrow(|images| images.get(name))
+            .filter(|image| has_access(user_id, &image.uploaded_by, name, Permission::Read))
+            .map(|image| (name.clone(), image))
+    });
+
+    let latest_crawl_result = STABLE_CRAWL_RESULTS.with_borrow(|results| {
+        entry
+            .crawl_keys
+            .iter()
+            .filter(|key| {
+                key.split_once(':').is_some_and(|(owner, name)| {
+                    has_access(user_id, owner, name, Permission::Read)
+                })
+            })
+            .filter_map(|key| results.get(key))
+            .max_by_key(|result| result.last_update)
+    });
+    if image.is_none() && latest_crawl_result.is_none() {
+        return None;
+    }
+
+    Some(PredictionDetails {
+        prediction_id: prediction_id.to_string(),
+        image_name: image.as_ref().map(|(name, _)| name.clone()),
+        uploaded_by: image.as_ref().map(|(_, image)| image.uploaded_by.clone()),
+        content_size: image.as_ref().map(|(_, image)| image.content.len() as u64),
+        image_hashes: entry.image_hashes,
+        latest_crawl_result,
+    })
+}
+
+/// Look up the image, owner, registered hashes and latest crawl result of a
+/// prediction. Only images and results `user_id` may read are returned.
+#[ic_cdk::query]
+fn get_by_prediction_id(
+    user_id: String,
+    prediction_id: String,
+) -> Result<PredictionDetails, SentinelError> {
+    if prediction_id.is_empty() {
+        return Err(SentinelError::invalid("prediction_id", "Prediction ID cannot be empty."));
+    }
+
+    prediction_details(&user_id, &prediction_id)
+        .ok_or_else(|| SentinelError::not_found(format!("Prediction '{}' not found.", prediction_id)))
+}
+
+/// Batched `get_by_prediction_id`; unknown or unreadable prediction ids are skipped
+#[ic_cdk::query]
+fn list_by_prediction_ids(
+    user_id: String,
+    prediction_ids: Vec<String>,
+) -> Result<Vec<PredictionDetails>, SentinelError> {
+    if prediction_ids.len() > MAX_BATCH_SIZE {
+        return Err(SentinelError::invalid(
+            "prediction_ids",
+            format!(
+                "At most {} prediction IDs can be requested at once.",
+                MAX_BATCH_SIZE
+            ),
+        ));
+    }
+
+    Ok(prediction_ids
+        .iter()
+        .filter_map(|prediction_id| prediction_details(&user_id, prediction_id))
+        .collect())
+}
+
+/// Detect an image by name, validating the user ID and storing the result.
+/// When the crawl fails transiently, a crawl job retries it with backoff; its
+/// ID is recorded in the image's `last_error`.
+#[ic_cdk::update]
+async fn detect_image(
+    user_id: String,
+    prediction_id: String,
+    name: String,
+    provider: Option<DetectionProvider>,
+) -> Result<String, SentinelError> {
+    let audit = AuditCall::begin(
+        "detect_image",
+        Some(&user_id),
+        format!("prediction_id: {}, name: {}, provider: {:?}", prediction_id, name, provider),
+    );
+    let providers = vec![provider.unwrap_or_default()];
+    let crawl = crawl_stored_image(user_id.clone(), prediction_id.clone(), name.clone(), providers);
+    let result = with_crawl_credit(&user_id, 1, crawl).await;
+    let retry_job_id = queue_crawl_retry(&result, &user_id, &prediction_id, &name, provider, None);
+    record_crawl_outcome(&user_id, &name, &result, retry_job_id);
+    audit.finish(result.map_err(SentinelError::from))
+}
+
+/// Crawl a stored image with several providers at once and merge their
+/// results into deduplicated, scored matches. Each provider queried costs a
+/// crawl credit and counts as a crawl against quotas and budgets. Like
+/// `detect_image`, a transient failure queues a retry job.
+#[ic_cdk::update]
+async fn detect_image_aggregated(
+    user_id: String,
+    prediction_id: String,
+    name: String,
+    providers: Vec<DetectionProvider>,
+) -> Result<String, SentinelError> {
+    let audit = AuditCall::begin(
+        "detect_image_aggregated",
+        Some(&user_id),
+        format!("prediction_id: {}, name: {}, providers: {:?}", prediction_id, name, providers),
+    );
+    let mut unique_providers = Vec::new();
+    for provider in providers {
+        if !unique_providers.contains(&provider) {
+            unique_providers.push(provider);
+        }
+    }
+    let cost = unique_providers.len() as u64;
+    let crawl = crawl_stored_image(
+        user_id.clone(),
+        prediction_id.clone(),
+        name.clone(),
+        unique_providers.clone(),
+    );
+    let result = with_crawl_credit(&user_id, cost, crawl).await;
+    let retry_job_id =
+        queue_crawl_retry(&result, &user_id, &prediction_id, &name, None, Some(unique_providers));
+    record_crawl_outcome(&user_id, &name, &result, retry_job_id);
+    audit.finish(result.map_err(SentinelError::from))
+}
+
+async fn crawl_stored_image(
+    user_id: String,
+    prediction_id: String,
+    name: String,
+    providers: Vec<DetectionProvider>,
+) -> Result<Detection, CrawlError> {
+    let stored_image = STABLE_IMAGES.with_borrow(|images| images.get(&name));
+    if let Some(image) = stored_image {
+        if !has_access(&user_id, &image.uploaded_by, &name, Permission::Crawl) {
+            let err = SentinelError::access_denied("Access denied: You do not own this image.");
+            return Err(err.into());
+        }
+        // Results always belong to the image owner, also when a grantee crawls
+        let owner = image.uploaded_by.clone();
+        let crawls = providers.len() as u64;
+        let subject_id = image.subject_id.as_deref();
+        let content = &image.content;
+        let detection =
+            run_detection(&providers, &user_id, &owner, subject_id, &prediction_id, &name, content);
+        let budgeted = with_org_crawl_budget(&owner, crawls, detection);
+        with_crawl_quota(&user_id, crawls, budgeted).await
     } else {
-        match serde_json::to_string(&user_results) {
-            Ok(json_string) => {
-                ic_cdk::println!("Serialized results: '{}'", json_string);
-                Ok(json_string)
+        Err(SentinelError::not_found(format!("Image '{}' not found.", name)).into())
+    }
+}
+
+
+#[ic_cdk::update]
+async fn detect_image_with_content(
+    user_id: String,
+    prediction_id: String,
+    name: String,
+    content: Vec<u8>,
+    provider: Option<DetectionProvider>,
+) -> Result<String, SentinelError> {
+    let audit = AuditCall::begin(
+        "detect_image_with_content",
+        Some(&user_id),
+        format!(
+            "prediction_id: {}, name: {}, content: {} bytes, provider: {:?}",
+            prediction_id,
+            name,
+            content.len(),
+            provider
+        ),
+    );
+    let providers = vec![provider.unwrap_or_default()];
+    let crawl = crawl_image_content(user_id.clone(), prediction_id, name, content, providers);
+    audit.finish(with_crawl_credit(&user_id, 1, crawl).await.map_err(SentinelError::from))
+}
+
+async fn crawl_image_content(
+    user_id: String,
+    prediction_id: String,
+    name: String,
+    content: Vec<u8>,
+    providers: Vec<DetectionProvider>,
+) -> Result<Detection, CrawlError> {
+    if user_id.is_empty() {
+        return Err(SentinelError::invalid("user_id", "User ID cannot be empty.").into());
+    }
+    if name.is_empty() {
+        return Err(SentinelError::invalid("name", "Image name cannot be empty.").into());
+    }
+    if content.is_empty() {
+        return Err(SentinelError::invalid("content", "Image content cannot be empty.").into());
+    }
+    let crawls = providers.len() as u64;
+    let detection =
+        run_detection(&providers, &user_id, &user_id, None, &prediction_id, &name, &content);
+    let budgeted = with_org_crawl_budget(&user_id, crawls, detection);
+    with_crawl_quota(&user_id, crawls, budgeted).await
+}
+
+/// A finished crawl: its JSON response and how many of the queried providers
+/// failed, which are not charged for
+struct Detection {
+    response: String,
+    failed_providers: u64,
+}
+
+/// Search `providers` for copies of `content`, paid by `user_id`, and store
+/// the normalized result under `owner`. Results of several providers are
+/// merged; the crawl only fails when every provider fails. Matches count as
+/// unauthorized once the consent of `subject_id` was revoked.
+async fn run_detection(
+    providers: &[DetectionProvider],
+    user_id: &str,
+    owner: &str,
+    subject_id: Option<&str>,
+    prediction_id: &str,
+    name: &str,
+    content: &[u8],
+) -> Result<Detection, CrawlError> {
+    let mut failed_providers = 0;
+    let mut parsed_result = match providers {
+        [] => {
+            let err = SentinelError::invalid("providers", "At least one provider is required.");
+            return Err(err.into());
+        }
+        [provider] => search_provider(*provider, user_id, prediction_id, name, content).await?,
+        _ => {
+            let searches = providers
+                .iter()
+                .map(|provider| search_provider(*provider, user_id, prediction_id, name, content));
+            let mut results = Vec::new();
+            let mut first_error = None;
+            for result in futures::future::join_all(searches).await {
+                match result {
+                    Ok(result) => results.push(result),
+                    Err(err) => {
+                        ic_cdk::println!("Provider failed for image '{}': {}", name, err.error());
+                        failed_providers += 1;
+                        first_error.get_or_insert(err);
+                    }
+                }
             }
-            Err(err) => {
-                ic_cdk::println!("Failed to serialize results: {}", err);
-                Err("Failed to serialize results.".to_string())
+            if let (true, Some(err)) = (results.is_empty(), first_error) {
+                return Err(err);
+            }
+            merge_crawl_results(results)
+        }
+    };
+
+    // Assign the prediction_id explicitly
+    parsed_result.prediction_id = prediction_id.to_string();
+    parsed_result.consent_revoked_at = consent_revoked_at(subject_id, prediction_id);
+    parsed_result.set_last_update_to_now();
+    record_match_history(owner, name, &mut parsed_result, time());
+
+    // Store the result
+    store_crawl_result(owner.to_string(), name.to_string(), parsed_result.clone())
+        .map_err(|e| SentinelError::internal(format!("Failed to store crawl result: {}", e)))?;
+
+    // Serialize the response as JSON
+    let response = serde_json::to_string(&parsed_result)
+        .map_err(|_| SentinelError::internal("Failed to serialize response."))?;
+
+    ic_cdk::println!("Ok response: '{}'", response);
+    Ok(Detection { response, failed_providers })
+}
+
+/// Query a single provider and parse its normalized response
+async fn search_provider(
+    provider: DetectionProvider,
+    user_id: &str,
+    prediction_id: &str,
+    name: &str,
+    content: &[u8],
+) -> Result<CrawlResult, CrawlError> {
+    let endpoint = provider_endpoint(provider)?;
+
+    ic_cdk::println!("Start crawling for image: '{}' with {:?}", name, provider);
+
+    // Providers of one aggregated crawl must not share a key, or a retry of one
+    // would replay another's response
+    let idempotency_key = format!("{}:{:?}", prediction_id, provider);
+    let request = provider.request(&endpoint, name, content, &idempotency_key);
+    let response = paid_http_request(user_id, name, request).await?;
+
+    let str_body = String::from_utf8(response.body)
+        .map_err(|_| SentinelError::parse_error("Failed to parse UTF-8 response."))?;
+
+    let mut parsed_result: CrawlResult = serde_json::from_str(&str_body)
+        .map_err(|_| SentinelError::parse_error("Failed to parse crawl result."))?;
+    parsed_result.source = Some(provider);
+    parsed_result.complete_matches(provider);
+    Ok(parsed_result)
+}
+
+/// Update the seen history of `owner`'s image with a crawl result and date
+/// every match in the result back to when its URL was first seen. URLs that
+/// an answering provider reported before but not this time are marked gone.
+fn record_match_history(owner: &str, name: &str, result: &mut CrawlResult, now: u64) {
+    let key = format!("{}:{}", owner, name);
+    let answered = result.sources.clone().unwrap_or_else(|| result.source.into_iter().collect());
+    let mut history = STABLE_MATCH_HISTORY
+        .with_borrow(|histories| histories.get(&key))
+        .unwrap_or_default();
+
+    let mut found_urls = Vec::new();
+    for found in result.matches.iter_mut().flatten() {
+        let url = canonical_url(&found.url);
+        let index = match history.urls.iter().position(|seen| seen.url == url) {
+            Some(index) => index,
+            None => {
+                history.urls.push(SeenUrl {
+                    url: url.clone(),
+                    page_url: None,
+                    domain: found.domain.clone(),
+                    match_type: found.match_type,
+                    first_seen: now,
+                    last_seen: now,
+                    gone_since: None,
+                    times_seen: 0,
+                    sources: Vec::new(),
+                });
+                history.urls.len() - 1
+            }
+        };
+
+        let seen = &mut history.urls[index];
+        if !found_urls.contains(&url) {
+            seen.times_seen += 1;
+            seen.last_seen = now;
+            seen.gone_since = None;
+            seen.match_type = seen.match_type.min(found.match_type);
+            found_urls.push(url);
+        }
+        if found.page_url.is_some() {
+            seen.page_url = found.page_url.clone();
+        }
+        for source in &found.sources {
+            if !seen.sources.contains(source) {
+                seen.sources.push(*source);
+            }
+        }
+        found.first_seen = seen.first_seen;
+    }
+
+    for seen in &mut history.urls {
+        let reported_by_answering = seen.sources.iter().any(|source| answered.contains(source));
+        if seen.gone_since.is_none() && reported_by_answering && !found_urls.contains(&seen.url) {
+            seen.gone_since = Some(now);
+        }
+    }
+
+    STABLE_MATCH_HISTORY.with_borrow_mut(|histories| histories.insert(key, history));
+}
+
+/// Merge the results of several providers, deduplicating matches by canonical
+/// URL. A match keeps the strongest type any provider reported and the first
+/// page it was seen on, and matches are ordered by score.
+fn merge_crawl_results(results: Vec<CrawlResult>) -> CrawlResult {
+    let last_update = results.iter().map(|result| result.last_update).max().unwrap_or_default();
+    let mut sources = Vec::new();
+    let mut web_entities = Vec::new();
+    let mut matches: Vec<Match> = Vec::new();
+    let mut match_index: HashMap<String, usize> = HashMap::new();
+
+    for result in results {
+        let source = result.source.unwrap_or_default();
+        if !sources.contains(&source) {
+            sources.push(source);
+        }
+        for entity in &result.web_entities {
+            push_unique(&mut web_entities, entity);
+        }
+
+        for mut found in result.matches.unwrap_or_default() {
+            found.url = canonical_url(&found.url);
+            found.page_url = found.page_url.map(|page_url| canonical_url(&page_url));
+            match match_index.get(&found.url) {
+                Some(&index) => {
+                    let existing = &mut matches[index];
+                    existing.match_type = existing.match_type.min(found.match_type);
+                    if existing.page_url.is_none() && found.page_url.is_some() {
+                        existing.page_url = found.page_url;
+                        existing.page_title = found.page_title;
+                        existing.domain = found.domain;
+                    }
+                    existing.thumbnail_url = existing.thumbnail_url.take().or(found.thumbnail_url);
+                    if !existing.sources.contains(&source) {
+                        existing.sources.push(source);
+                    }
+                }
+                None => {
+                    match_index.insert(found.url.clone(), matches.len());
+                    found.sources = vec![source];
+                    matches.push(found);
+                }
+            }
+        }
+    }
+
+    for found in &mut matches {
+        found.score = Some(found.sources.len() as f64 / sources.len() as f64);
+    }
+    matches.sort_by(|a, b| b.score.unwrap_or_default().total_cmp(&a.score.unwrap_or_default()));
+
+    let mut merged = CrawlResult {
+        prediction_id: String::new(),
+        web_entities,
+        full_matching_images: Vec::new(),
+        pages_with_matching_images: Vec::new(),
+        visually_similar_images: Vec::new(),
+        last_update,
+        consent_revoked_at: None,
+        source: None,
+        provider_job_id: None,
+        sources: Some(sources),
+        matches: Some(matches),
+    };
+    merged.derive_url_lists();
+    merged
+}
+
+fn is_tracking_param(name: &str) -> bool {
+    name.starts_with("utm_") || TRACKING_PARAMS.contains(&name)
+}
+
+/// Canonical form of a matched URL, so that providers reporting the same page
+/// agree: https scheme, lower-case host without `www.` or default port, no
+/// fragment and no tracking query parameters. Other URLs are kept as they are.
+fn canonical_url(url: &str) -> String {
+    let url = url.trim();
+    let Some((scheme, rest)) = url.split_once("://") else {
+        return url.to_string();
+    };
+    if !scheme.eq_ignore_ascii_case("http") && !scheme.eq_ignore_ascii_case("https") {
+        return url.to_string();
+    }
+
+    let rest = rest.split('#').next().unwrap_or_default();
+    let (authority, path_and_query) = rest.split_at(rest.find(['/', '?']).unwrap_or(rest.len()));
+    let authority = authority.to_ascii_lowercase();
+    let host = authority
+        .strip_suffix(":80")
+        .or_else(|| authority.strip_suffix(":443"))
+        .unwrap_or(&authority);
+    let host = host.strip_prefix("www.").unwrap_or(host);
+
+    let (path, query) = path_and_query.split_once('?').unwrap_or((path_and_query, ""));
+    let path = if path.is_empty() { "/" } else { path };
+    let params: Vec<&str> = query
+        .split('&')
+        .filter(|param| !param.is_empty())
+        .filter(|param| !is_tracking_param(param.split('=').next().unwrap_or_default()))
+        .collect();
+
+    if params.is_empty() {
+        format!("https://{}{}", host, path)
+    } else {
+        format!("https://{}{}?{}", host, path, params.join("&"))
+    }
+}
+
+/// Host of a URL as `canonical_url` writes it, e.g. `example.com`
+fn url_domain(url: &str) -> String {
+    canonical_url(url)
+        .strip_prefix("https://")
+        .and_then(|rest| rest.split(['/', '?']).next())
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// Queue a crawl of a stored image and return the job ID to poll with `get_crawl_job`
+#[ic_cdk::update]
+fn enqueue_crawl(
+    user_id: String,
+    prediction_id: String,
+    name: String,
+    provider: Option<DetectionProvider>,
+) -> Result<u64, SentinelError> {
+    AuditCall::begin(
+        "enqueue_crawl",
+        Some(&user_id),
+        format!("prediction_id: {}, name: {}, provider: {:?}", prediction_id, name, provider),
+    )
+    .run(move || {
+        let Some(image) = STABLE_IMAGES.with_borrow(|images| images.get(&name)) else {
+            return Err(SentinelError::not_found(format!("Image '{}' not found.", name)));
+        };
+        if !has_access(&user_id, &image.uploaded_by, &name, Permission::Crawl) {
+            return Err(SentinelError::access_denied("Access denied: You do not own this image."));
+        }
+
+        let id = insert_crawl_job(user_id, prediction_id, name, provider, None, None);
+        ic_cdk_timers::set_timer(Duration::ZERO, process_crawl_queue);
+        Ok(id)
+    })
+}
+
+fn insert_crawl_job(
+    user_id: String,
+    prediction_id: String,
+    name: String,
+    provider: Option<DetectionProvider>,
+    providers: Option<Vec<DetectionProvider>>,
+    error: Option<String>,
+) -> u64 {
+    STABLE_CRAWL_JOBS.with_borrow_mut(|jobs| {
+        let id = jobs.last_key_value().map_or(0, |(id, _)| id + 1);
+        jobs.insert(
+            id,
+            CrawlJob {
+                id,
+                user_id,
+                prediction_id,
+                image_name: name,
+                status: CrawlJobStatus::Queued,
+                result: None,
+                error,
+                enqueued_at: time(),
+                started_at: None,
+                finished_at: None,
+                attempts: Some(0),
+                retry_at: None,
+                provider,
+                providers,
+            },
+        );
+        STABLE_ACTIVE_CRAWL_JOBS.with_borrow_mut(|active| active.insert(id, ()));
+        id
+    })
+}
+
+/// Queue a crawl job retrying a direct crawl that failed transiently. The
+/// failed call counts as the job's first attempt, so the job waits out the
+/// first backoff before it runs.
+fn queue_crawl_retry(
+    result: &Result<String, CrawlError>,
+    user_id: &str,
+    prediction_id: &str,
+    name: &str,
+    provider: Option<DetectionProvider>,
+    providers: Option<Vec<DetectionProvider>>,
+) -> Option<u64> {
+    let Err(CrawlError::Transient(err)) = result else {
+        return None;
+    };
+    let error = Some(err.to_string());
+    let id = insert_crawl_job(
+        user_id.to_string(),
+        prediction_id.to_string(),
+        name.to_string(),
+        provider,
+        providers,
+        error,
+    );
+    let delay = crawl_retry_delay(id, 1);
+    update_crawl_job(id, |job| {
+        job.attempts = Some(1);
+        job.retry_at = Some(time() + delay.as_nanos() as u64);
+    });
+    ic_cdk_timers::set_timer(delay, process_crawl_queue);
+    ic_cdk::println!("Crawl of image '{}' failed transiently, retrying as job {}", name, id);
+    Some(id)
+}
+
+fn update_crawl_job(id: u64, update: impl FnOnce(&mut CrawlJob)) {
+    STABLE_CRAWL_JOBS.with_borrow_mut(|jobs| {
+        if let Some(mut job) = jobs.get(&id) {
+            update(&mut job);
+            index_crawl_job(&job);
+            jobs.insert(id, job);
+        }
+    });
+}
+
+/// Keep `STABLE_ACTIVE_CRAWL_JOBS` in step with the status of a job
+fn index_crawl_job(job: &CrawlJob) {
+    STABLE_ACTIVE_CRAWL_JOBS.with_borrow_mut(|active| match job.status {
+        CrawlJobStatus::Queued | CrawlJobStatus::Running => active.insert(job.id, ()),
+        CrawlJobStatus::Succeeded | CrawlJobStatus::Failed => active.remove(&job.id),
+    });
+}
+
+/// Revoke grants and cancel pending offers with the old portfolio scope, which
+/// covered every image of the owner rather than those of one subject
+fn retire_portfolio_scopes() {
+    let now = time();
+    STABLE_ACCESS_GRANTS.with_borrow_mut(|grants| {
+        let retired: Vec<AccessGrant> = grants
+            .values()
+            .filter(|grant| matches!(grant.scope, ContentScope::Portfolio))
+            .filter(|grant| grant.revoked_at.is_none())
+            .collect();
+        for mut grant in retired {
+            grant.revoked_at = Some(now);
+            grants.insert(grant.id, grant);
+        }
+    });
+    let retired: Vec<TransferOffer> = STABLE_TRANSFER_OFFERS.with_borrow(|offers| {
+        offers
+            .values()
+            .filter(|offer| matches!(offer.scope, ContentScope::Portfolio))
+            .filter(|offer| offer.status == TransferStatus::Pending)
+            .collect()
+    });
+    for offer in retired {
+        resolve_offer(offer, TransferStatus::Cancelled);
+    }
+}
+
+/// Index the unfinished jobs, which were not tracked before schema version 3
+fn rebuild_active_crawl_jobs() {
+    STABLE_ACTIVE_CRAWL_JOBS.with_borrow_mut(|active| active.clear_new());
+    STABLE_CRAWL_JOBS.with_borrow(|jobs| {
+        for job in jobs.values() {
+            index_crawl_job(&job);
+        }
+    });
+}
+
+/// Drop jobs that finished more than `CRAWL_JOB_RETENTION` ago. Job ids grow
+/// with `enqueued_at`, so the scan stops at the first job enqueued after the cutoff.
+fn prune_finished_crawl_jobs() {
+    let cutoff = time().saturating_sub(CRAWL_JOB_RETENTION.as_nanos() as u64);
+    let expired: Vec<u64> = STABLE_CRAWL_JOBS.with_borrow(|jobs| {
+        jobs.values()
+            .take_while(|job| job.enqueued_at < cutoff)
+            .filter(|job| job.finished_at.is_some_and(|finished_at| finished_at < cutoff))
+            .map(|job| job.id)
+            .collect()
+    });
+    STABLE_CRAWL_JOBS.with_borrow_mut(|jobs| {
+        for id in expired {
+            jobs.remove(&id);
+        }
+    });
+}
+
+/// Start queued jobs, oldest first, while fewer than `MAX_CONCURRENT_CRAWLS` are running
+fn process_crawl_queue() {
+    let now = time();
+    let active: Vec<u64> = STABLE_ACTIVE_CRAWL_JOBS.with_borrow(|active| active.keys().collect());
+    let (running, queued) = STABLE_CRAWL_JOBS.with_borrow(|jobs| {
+        let mut running = 0;
+        let mut queued = Vec::new();
+        for job in active.iter().filter_map(|id| jobs.get(id)) {
+            match job.status {
+                CrawlJobStatus::Running => running += 1,
+                CrawlJobStatus::Queued if job.retry_at.is_none_or(|retry_at| retry_at <= now) => {
+                    queued.push(job.id)
+                }
+                CrawlJobStatus::Queued => {}
+                CrawlJobStatus::Succeeded | CrawlJobStatus::Failed => {}
+            }
+        }
+        (running, queued)
+    });
+
+    for id in queued.into_iter().take(MAX_CONCURRENT_CRAWLS.saturating_sub(running)) {
+        update_crawl_job(id, |job| {
+            job.status = CrawlJobStatus::Running;
+            job.started_at = Some(now);
+            job.attempts = Some(job.attempts.unwrap_or(0) + 1);
+            job.retry_at = None;
+        });
+        ic_cdk::spawn(run_crawl_job(id));
+    }
+}
+
+async fn run_crawl_job(id: u64) {
+    let Some(job) = STABLE_CRAWL_JOBS.with_borrow(|jobs| jobs.get(&id)) else {
+        return;
+    };
+    ic_cdk::println!("Running crawl job {} for image '{}'", id, job.image_name);
+
+    let providers = job.providers.unwrap_or_else(|| vec![job.provider.unwrap_or_default()]);
+    let cost = providers.len() as u64;
+    let crawl = crawl_stored_image(
+        job.user_id.clone(),
+        job.prediction_id,
+        job.image_name.clone(),
+        providers,
+    );
+    let result = with_crawl_credit(&job.user_id, cost, crawl).await;
+    let attempts = job.attempts.unwrap_or(1);
+    let retrying = matches!(result, Err(CrawlError::Transient(_))) && attempts < MAX_CRAWL_ATTEMPTS;
+    record_crawl_outcome(&job.user_id, &job.image_name, &result, retrying.then_some(id));
+
+    update_crawl_job(id, |job| match result {
+        Ok(response) => {
+            job.status = CrawlJobStatus::Succeeded;
+            job.result = Some(response);
+            job.error = None;
+            job.finished_at = Some(time());
+        }
+        Err(CrawlError::Transient(message)) if attempts < MAX_CRAWL_ATTEMPTS => {
+            let delay = crawl_retry_delay(id, attempts);
+            ic_cdk::println!("Crawl job {} failed transiently, retrying in {:?}: {}", id, delay, message);
+            job.status = CrawlJobStatus::Queued;
+            job.error = Some(message.to_string());
+            job.retry_at = Some(time() + delay.as_nanos() as u64);
+            ic_cdk_timers::set_timer(delay, process_crawl_queue);
+        }
+        Err(CrawlError::Transient(message)) => {
+            job.status = CrawlJobStatus::Failed;
+            job.error = Some(format!("Gave up after {} attempts: {}", attempts, message));
+            job.finished_at = Some(time());
+        }
+        Err(CrawlError::Permanent(message)) => {
+            job.status = CrawlJobStatus::Failed;
+            job.error = Some(message.to_string());
+            job.finished_at = Some(time());
+        }
+    });
+    process_crawl_queue();
+}
+
+/// Exponential backoff from `CRAWL_RETRY_BASE_DELAY`, capped at
+/// `CRAWL_RETRY_MAX_DELAY`, with up to half of the delay as jitter so that
+/// jobs failing together do not retry together
+fn crawl_retry_delay(job_id: u64, attempts: u32) -> Duration {
+    let backoff = CRAWL_RETRY_BASE_DELAY
+        .saturating_mul(2u32.saturating_pow(attempts.saturating_sub(1)))
+        .min(CRAWL_RETRY_MAX_DELAY);
+    let half = backoff.as_millis() as u64 / 2;
+    let seed = Sha256::digest([job_id.to_be_bytes(), time().to_be_bytes()].concat());
+    let jitter = u64::from_be_bytes(seed[..8].try_into().unwrap()) % (half + 1);
+    Duration::from_millis(half + jitter)
+}
+
+/// Keep the last crawl failure of a stored image, or clear it after a success.
+/// Failures of users who may not crawl the image are not recorded.
+fn record_crawl_outcome(
+    user_id: &str,
+    name: &str,
+    result: &Result<String, CrawlError>,
+    retry_job_id: Option<u64>,
+) {
+    let last_error = result.as_ref().err().map(|err| CrawlErrorRecord {
+        message: err.error().to_string(),
+        transient: matches!(err, CrawlError::Transient(_)),
+        occurred_at: time(),
+        retry_job_id,
+    });
+    STABLE_IMAGES.with_borrow_mut(|images| {
+        if let Some(mut image) = images.get(&name.to_string()) {
+            if !has_access(user_id, &image.uploaded_by, name, Permission::Crawl) {
+                return;
+            }
+            image.last_error = last_error;
+            images.insert(name.to_string(), image);
+        }
+    });
+}
+
+/// Jobs that were running when the canister was upgraded lost their outcall; run them again
+fn requeue_interrupted_crawl_jobs() {
+    let active: Vec<u64> = STABLE_ACTIVE_CRAWL_JOBS.with_borrow(|active| active.keys().collect());
+    let interrupted: Vec<u64> = STABLE_CRAWL_JOBS.with_borrow(|jobs| {
+        active
+            .iter()
+            .filter_map(|id| jobs.get(id))
+            .filter(|job| job.status == CrawlJobStatus::Running)
+            .map(|job| job.id)
+            .collect()
+    });
+    for id in interrupted {
+        update_crawl_job(id, |job| {
+            job.status = CrawlJobStatus::Queued;
+            job.started_at = None;
+        });
+    }
+}
+
+/// A crawl job of the user. Finished jobs are pruned after `CRAWL_JOB_RETENTION`.
+#[ic_cdk::query]
+fn get_crawl_job(user_id: String, job_id: u64) -> Result<CrawlJob, SentinelError> {
+    match STABLE_CRAWL_JOBS.with_borrow(|jobs| jobs.get(&job_id)) {
+        Some(job) if job.user_id == user_id => Ok(job),
+        Some(_) => Err(SentinelError::access_denied(
+            "Access denied: This crawl job belongs to another user.",
+        )),
+        None => Err(SentinelError::not_found(format!("Crawl job {} not found.", job_id))),
+    }
+}
+
+/// Crawl jobs of a user, newest first
+#[ic_cdk::query]
+fn list_crawl_jobs(user_id: String) -> Vec<CrawlJob> {
+    STABLE_CRAWL_JOBS.with_borrow(|jobs| {
+        jobs.iter()
+            .rev()
+            .filter(|(_, job)| job.user_id == user_id)
+            .map(|(_, job)| job)
+            .collect()
+    })
+}
+
+/// A reverse image search backend queried through an HTTP outcall. Replicas
+/// have to agree on the response, so `transform` reduces every backend's
+/// response body to the JSON fields of `CrawlResult`.
+trait ReverseImageSearch {
+    /// Multipart form field the image is uploaded in
+    fn upload_field(&self) -> &'static str;
+
+    /// Header carrying the endpoint's API key, for backends that need one
+    fn api_key_header(&self) -> Option<&'static str>;
+
+    /// Reduce a successful response body to the fields of `CrawlResult`,
+    /// leaving out volatile fields such as timestamps
+    fn normalize(&self, body: &serde_json::Value) -> Option<serde_json::Value>;
+}
+
+/// Shootify's proxy, which already answers in the `CrawlResult` format
+struct ShootifyProxySearch;
+
+impl ReverseImageSearch for ShootifyProxySearch {
+    fn upload_field(&self) -> &'static str {
+        "image"
+    }
+
+    fn api_key_header(&self) -> Option<&'static str> {
+        None
+    }
+
+    fn normalize(&self, body: &serde_json::Value) -> Option<serde_json::Value> {
+        // Only copy these fields over into a new JSON object; `created_at`
+        // and `updated_at` differ between replicas and are left out
+        let fields_to_keep = [
+            "full_matching_images",
+            "pages_with_matching_images",
+            "visually_similar_images",
+            "web_entities",
+        ];
+
+        let obj = body.as_object()?;
+        let mut new_map = serde_json::Map::new();
+        for key in &fields_to_keep {
+            if let Some(value) = obj.get(*key) {
+                new_map.insert((*key).to_string(), value.clone());
+            }
+        }
+        // The proxy's job ID may be a number or a string
+        match obj.get("id") {
+            Some(serde_json::Value::String(id)) => {
+                new_map.insert("provider_job_id".to_string(), id.clone().into());
             }
+            Some(id @ serde_json::Value::Number(_)) => {
+                new_map.insert("provider_job_id".to_string(), id.to_string().into());
+            }
+            _ => {}
         }
+        Some(serde_json::Value::Object(new_map))
     }
 }
 
-/// Retrieve an image by name, validating the user ID
-#[ic_cdk::query]
-fn get_image(user_id: String, name: String) -> Result<StoredImage, String> {
-    STABLE_IMAGES.with_borrow(|images| {
-        if let Some(image) = images.get(&name) {
-            if image.uploaded_by == user_id {
-                Ok(image)
-            } else {
-                Err("Access denied: You do not own this image.".to_string())
+/// TinEye search API: `results.matches[]` carry a score and thumbnail, and
+/// their `backlinks[]` the image URL and the page it was found on. TinEye does
+/// not label images, so there are no web entities or visually similar images.
+struct TinEyeSearch;
+
+impl ReverseImageSearch for TinEyeSearch {
+    fn upload_field(&self) -> &'static str {
+        "image_upload"
+    }
+
+    fn api_key_header(&self) -> Option<&'static str> {
+        Some("x-api-key")
+    }
+
+    fn normalize(&self, body: &serde_json::Value) -> Option<serde_json::Value> {
+        let mut matches = Vec::new();
+        for found in body.get("results")?.get("matches")?.as_array()? {
+            let score = found.get("score").and_then(|s| s.as_f64());
+            let thumbnail_url = found.get("image_url").and_then(|u| u.as_str());
+            for backlink in found.get("backlinks").and_then(|b| b.as_array()).into_iter().flatten() {
+                let Some(url) = backlink.get("url").and_then(|u| u.as_str()) else {
+                    continue;
+                };
+                matches.push(serde_json::json!({
+                    "url": url,
+                    "page_url": backlink.get("backlink").and_then(|p| p.as_str()),
+                    "thumbnail_url": thumbnail_url,
+                    "match_type": MatchType::FullMatch,
+                    "score": score,
+                }));
             }
-        } else {
-            Err("Image not found.".to_string())
         }
-    })
+        Some(serde_json::json!({
+            "web_entities": [],
+            "full_matching_images": [],
+            "pages_with_matching_images": [],
+            "visually_similar_images": [],
+            "matches": matches,
+        }))
+    }
 }
 
-/// List all images for a specific user ID
-#[ic_cdk::query]
-fn list_images(user_id: String) -> Vec<String> {
-    STABLE_IMAGES.with_borrow(|images| {
-        images
-            .iter()
-            .filter(|(_, image)| image.uploaded_by == user_id)
-            .map(|(key, _)| key.clone())
-            .collect()
-    })
-}
+/// Bing Visual Search: results come as `tags[].actions[]`, where
+/// `PagesIncluding` lists exact matches and `VisuallySimilarImages` similar
+/// ones, each with its host page, page title and thumbnail
+struct BingSearch;
 
-/// Delete an image by name, validating the user ID
-#[ic_cdk::update]
-fn delete_image(user_id: String, name: String) -> Result<(), String> {
-    STABLE_IMAGES.with_borrow_mut(|images| {
-        if let Some(image) = images.get(&name) {
-            if image.uploaded_by == user_id {
-                images.remove(&name);
-                ic_cdk::println!("Image '{}' deleted successfully by user '{}'", name, user_id);
-                Ok(())
-            } else {
-                Err("Access denied: You do not own this image.".to_string())
+impl ReverseImageSearch for BingSearch {
+    fn upload_field(&self) -> &'static str {
+        "image"
+    }
+
+    fn api_key_header(&self) -> Option<&'static str> {
+        Some("Ocp-Apim-Subscription-Key")
+    }
+
+    fn normalize(&self, body: &serde_json::Value) -> Option<serde_json::Value> {
+        let mut web_entities = Vec::new();
+        let mut matches = Vec::new();
+        for tag in body.get("tags")?.as_array()? {
+            if let Some(entity) = tag.get("displayName").and_then(|n| n.as_str()).filter(|n| !n.is_empty()) {
+                push_unique(&mut web_entities, entity);
+            }
+            for action in tag.get("actions").and_then(|a| a.as_array()).into_iter().flatten() {
+                let match_type = match action.get("actionType").and_then(|t| t.as_str()) {
+                    Some("PagesIncluding") => MatchType::FullMatch,
+                    Some("VisuallySimilarImages") => MatchType::VisuallySimilar,
+                    _ => continue,
+                };
+                let values = action.pointer("/data/value").and_then(|v| v.as_array());
+                for value in values.into_iter().flatten() {
+                    let Some(url) = value.get("contentUrl").and_then(|u| u.as_str()) else {
+                        continue;
+                    };
+                    matches.push(serde_json::json!({
+                        "url": url,
+                        "page_url": value.get("hostPageUrl").and_then(|p| p.as_str()),
+                        "page_title": value.get("name").and_then(|n| n.as_str()),
+                        "thumbnail_url": value.get("thumbnailUrl").and_then(|u| u.as_str()),
+                        "match_type": match_type,
+                    }));
+                }
             }
-        } else {
-            Err(format!("Image '{}' not found.", name))
         }
-    })
+        Some(serde_json::json!({
+            "web_entities": web_entities,
+            "full_matching_images": [],
+            "pages_with_matching_images": [],
+            "visually_similar_images": [],
+            "matches": matches,
+        }))
+    }
 }
 
-/// Detect an image by name, validating the user ID and storing the result
-#[ic_cdk::update]
-async fn detect_image(user_id: String, prediction_id: String, name: String) -> Result<String, String> {
-    let stored_image = STABLE_IMAGES.with_borrow(|images| images.get(&name));
-    if let Some(image) = stored_image {
-        if image.uploaded_by != user_id {
-            return Err("Access denied: You do not own this image.".to_string());
+impl DetectionProvider {
+    fn search(self) -> &'static dyn ReverseImageSearch {
+        match self {
+            DetectionProvider::ShootifyProxy => &ShootifyProxySearch,
+            DetectionProvider::TinEye => &TinEyeSearch,
+            DetectionProvider::Bing => &BingSearch,
         }
+    }
 
-        let host = "icp-api.shootify.io";
-        let url = "https://icp-api.shootify.io/api/v1/utils/icp-proxy/";
+    /// Upload `content` to `endpoint`, with a context telling `transform`
+    /// which response schema to expect
+    fn request(
+        self,
+        endpoint: &ProviderEndpoint,
+        name: &str,
+        content: &[u8],
+        idempotency_key: &str,
+    ) -> CanisterHttpRequestArgument {
         let boundary = "boundary123";
-        let idempotency_key = "UUID-123456789";
 
-        ic_cdk::println!("Start crawling for image: '{}'", name);
-
-        let request_headers = vec![
-            HttpHeader {
-                name: "Host".to_string(),
-                value: format!("{host}:443"),
-            },
+        let mut request_headers = vec![
             HttpHeader {
                 name: "User-Agent".to_string(),
                 value: "demo_HTTP_POST_canister".to_string(),
@@ -330,199 +5765,84 @@ async fn detect_image(user_id: String, prediction_id: String, name: String) -> R
                 value: format!("multipart/form-data; boundary={}", boundary),
             },
         ];
+        if let (Some(header), Some(api_key)) = (self.search().api_key_header(), &endpoint.api_key) {
+            request_headers.push(HttpHeader {
+                name: header.to_string(),
+                value: api_key.clone(),
+            });
+        }
 
         let body = format!(
-            "--{}\r\nContent-Disposition: form-data; name=\"image\"; filename=\"{}\"\r\nContent-Type: image/jpeg\r\n\r\n",
-            boundary, name
+            "--{}\r\nContent-Disposition: form-data; name=\"{}\"; filename=\"{}\"\r\nContent-Type: image/jpeg\r\n\r\n",
+            boundary,
+            self.search().upload_field(),
+            name
         );
 
         let mut body_bytes = body.into_bytes();
-        body_bytes.extend_from_slice(&image.content);
+        body_bytes.extend_from_slice(content);
         body_bytes.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
 
-        // Calculate the size of the body and expected response
-        let request_body_size = body_bytes.len() as u64;
-        let expected_response_size = 20_000_000; // Adjust based on expected response size
-
-        const BASE_CYCLES: u64 = 20_000_000_000; // Fixed cost for the request
-        const BODY_COST_PER_BYTE: u64 = 200; // Cost per byte of the request body
-        const RESPONSE_COST_PER_BYTE: u64 = 200; // Cost per byte of the expected response
-
-        // Calculate the cycles required
-        let cycles = BASE_CYCLES
-            + request_body_size * BODY_COST_PER_BYTE
-            + expected_response_size * RESPONSE_COST_PER_BYTE;
+        let context = Context { provider: self };
 
-        // Clone body_bytes to avoid moving
-        let body_clone = body_bytes.clone();
-
-        let request = CanisterHttpRequestArgument {
-            url: url.to_string(),
-            max_response_bytes: None,
+        CanisterHttpRequestArgument {
+            url: endpoint.url.clone(),
+            max_response_bytes: Some(max_response_bytes()),
             method: HttpMethod::POST,
             headers: request_headers,
-            body: Some(body_clone),
+            body: Some(body_bytes),
             transform: Some(TransformContext::from_name(
                 "transform".to_string(),
-                serde_json::to_vec(&Context {
-                    bucket_start_time_index: 0,
-                    closing_price_index: 4,
-                }).unwrap(),
+                serde_json::to_vec(&context).unwrap(),
             )),
-        };
-
-        ic_cdk::println!("Estimated cycles: '{}'", cycles);
-
-        match http_request(request, cycles.into()).await {
-            Ok((response,)) => {
-                let str_body = String::from_utf8(response.body)
-                    .map_err(|_| "Failed to parse UTF-8 response.".to_string())?;
-
-                let mut parsed_result: CrawlResult = serde_json::from_str(&str_body)
-                    .map_err(|_| "Failed to parse crawl result.".to_string())?;
-
-                // Assign the prediction_id explicitly
-                parsed_result.prediction_id = prediction_id.clone();
-                parsed_result.set_last_update_to_now();
-
-                // Store the result
-                store_crawl_result(user_id.clone(), name.clone(), parsed_result.clone())
-                    .map_err(|e| format!("Failed to store crawl result: {}", e))?;
-
-                // Serialize the response as JSON
-                let response = serde_json::to_string(&parsed_result)
-                    .map_err(|_| "Failed to serialize response.".to_string())?;
-
-                ic_cdk::println!("Ok response: '{}'", response);
-                Ok(response)
-            }
-            Err((r, m)) => {
-                let message = format!("HTTP request failed. RejectionCode: {r:?}, Error: {m}");
-                Err(message)
-            }
         }
-    } else {
-        Err(format!("Image '{}' not found.", name))
     }
 }
 
-
-#[ic_cdk::update]
-async fn detect_image_with_content(
-    user_id: String,
-    prediction_id: String,
-    name: String,
-    content: Vec<u8>,
-) -> Result<String, String> {
-    if user_id.is_empty() {
-        return Err("User ID cannot be empty.".to_string());
-    }
-    if name.is_empty() {
-        return Err("Image name cannot be empty.".to_string());
-    }
-    if content.is_empty() {
-        return Err("Image content cannot be empty.".to_string());
+/// Configured endpoint of `provider`; only the Shootify proxy has a default
+fn provider_endpoint(provider: DetectionProvider) -> Result<ProviderEndpoint, SentinelError> {
+    let configured = config()
+        .provider_endpoints
+        .unwrap_or_default()
+        .into_iter()
+        .find(|endpoint| endpoint.provider == provider);
+    match configured {
+        Some(endpoint) => Ok(endpoint),
+        None if provider == DetectionProvider::ShootifyProxy => Ok(ProviderEndpoint {
+            provider,
+            url: SHOOTIFY_PROXY_URL.to_string(),
+            api_key: None,
+        }),
+        None => Err(SentinelError::failed_precondition(format!(
+            "Detection provider {:?} is not configured.",
+            provider
+        ))),
     }
+}
 
-    let url = "https://icp-api.shootify.io/api/v1/utils/icp-proxy/";
-    let boundary = "boundary123";
-
-    ic_cdk::println!("Start crawling for image: '{}'", name);
-
-    let request_headers = vec![
-        HttpHeader {
-            name: "User-Agent".to_string(),
-            value: "demo_HTTP_POST_canister".to_string(),
-        },
-        HttpHeader {
-            name: "Idempotency-Key".to_string(),
-            value: prediction_id.clone(),
-        },
-        HttpHeader {
-            name: "Content-Type".to_string(),
-            value: format!("multipart/form-data; boundary={}", boundary),
-        },
-    ];
-
-    let body = format!(
-        "--{}\r\nContent-Disposition: form-data; name=\"image\"; filename=\"{}\"\r\nContent-Type: image/jpeg\r\n\r\n",
-        boundary, name
-    );
-
-    let mut body_bytes = body.into_bytes();
-    body_bytes.extend_from_slice(&content);
-    body_bytes.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
-
-    // Calculate the size of the body and expected response
-    let request_body_size = body_bytes.len() as u64;
-    let expected_response_size = 20_000_000; // Adjust based on expected response size
-
-    const BASE_CYCLES: u64 = 20_000_000_000; // Fixed cost for the request
-    const BODY_COST_PER_BYTE: u64 = 400; // Cost per byte of the request body
-    const RESPONSE_COST_PER_BYTE: u64 = 400; // Cost per byte of the expected response
-
-    // Calculate the cycles required
-    let cycles = BASE_CYCLES
-        + request_body_size * BODY_COST_PER_BYTE
-        + expected_response_size * RESPONSE_COST_PER_BYTE;
-
-    let context = Context {
-        bucket_start_time_index: 0,
-        closing_price_index: 4,
-    };
-
-    let request = CanisterHttpRequestArgument {
-        url: url.to_string(),
-        max_response_bytes: None,
-        method: HttpMethod::POST,
-        headers: request_headers,
-        body: Some(body_bytes),
-        transform: Some(TransformContext::from_name(
-            "transform".to_string(),
-             serde_json::to_vec(&context).unwrap(),
-                                )),
-
-        };
-
-    ic_cdk::println!("Estimated cycles: '{}'", cycles);
-
-    match http_request(request, cycles.into()).await {
-        Ok((response,)) => {
-           ic_cdk::api::print(format!("Raw response: {:?}", response.clone()));
-            let str_body = String::from_utf8(response.body)
-                .map_err(|_| "Failed to parse UTF-8 response.".to_string())?;
-
-            let mut parsed_result: CrawlResult = serde_json::from_str(&str_body)
-                .map_err(|_| "Failed to parse crawl result.".to_string())?;
-
-            // Assign the prediction_id explicitly
-            parsed_result.prediction_id = prediction_id.clone();
-            parsed_result.set_last_update_to_now();
-
-            // Store the result
-            store_crawl_result(user_id.clone(), name.clone(), parsed_result.clone())
-                .map_err(|e| format!("Failed to store crawl result: {}", e))?;
-
-            // Serialize the response as JSON
-            let response = serde_json::to_string(&parsed_result)
-                .map_err(|_| "Failed to serialize response.".to_string())?;
-
-            ic_cdk::println!("Ok response: '{}'", response);
-            Ok(response)
-        }
-        Err((r, m)) => {
-            let message = format!("HTTP request failed. RejectionCode: {r:?}, Error: {m}");
-            Err(message)
+/// Sort and deduplicate every array so that replicas receiving the same
+/// matches in a different order produce identical bytes. Object keys are
+/// already sorted by `serde_json::Map`.
+fn sort_json_arrays(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Array(items) => {
+            items.iter_mut().for_each(sort_json_arrays);
+            items.sort_by_cached_key(|item| item.to_string());
+            items.dedup();
         }
+        serde_json::Value::Object(map) => map.values_mut().for_each(sort_json_arrays),
+        _ => {}
     }
 }
 
-
 #[ic_cdk::query]
 fn transform(raw: TransformArgs) -> HttpResponse {
     ic_cdk::println!("Start transformation function");
     ic_cdk::println!("Raw transform arguments: {:#?}", raw);
 
+    // Requests sent before the context named a provider all went to the proxy
+    let context: Context = serde_json::from_slice(&raw.context).unwrap_or_default();
+
     let headers = vec![
         HttpHeader {
             name: "Content-Security-Policy".to_string(),
@@ -554,42 +5874,17 @@ fn transform(raw: TransformArgs) -> HttpResponse {
         status: raw.response.status.clone(),
         body: vec![],
         headers,
-        ..Default::default()
     };
 
-    if res.status == Nat::from(200u32) {
-        if let Ok(original_value) = serde_json::from_slice::<serde_json::Value>(&raw.response.body) {
-            // Only copy these fields over into a new JSON object
-            let fields_to_keep = [
-                "created_at",
-                "full_matching_images",
-                "id",
-                "pages_with_matching_images",
-                "updated_at",
-                "visually_similar_images",
-                "web_entities",
-            ];
-
-            // If the top-level value is a JSON object, copy only the fields we want
-            if let Some(obj) = original_value.as_object() {
-                let mut new_map = serde_json::Map::new();
-
-                // For each field we want, if it exists in obj, copy it
-                for key in &fields_to_keep {
-                    if let Some(value) = obj.get(*key) {
-                        new_map.insert((*key).to_string(), value.clone());
-                    }
-                }
-
-                // Convert that new map back to a serde_json::Value
-                let filtered_value = serde_json::Value::Object(new_map);
-
-                // Serialize that filtered JSON into bytes for the response
-                if let Ok(filtered_body_bytes) = serde_json::to_vec(&filtered_value) {
-                    res.body = filtered_body_bytes;
-                }
-            } else {
-                // The JSON wasn't an object (maybe an array or string?), so just leave it unchanged or handle it as you wish
+    if res.status == 200u32 {
+        let normalized = serde_json::from_slice::<serde_json::Value>(&raw.response.body)
+            .ok()
+            .and_then(|original_value| context.provider.search().normalize(&original_value));
+        if let Some(mut normalized) = normalized {
+            sort_json_arrays(&mut normalized);
+            // Serialize the normalized JSON into bytes for the response
+            if let Ok(normalized_body_bytes) = serde_json::to_vec(&normalized) {
+                res.body = normalized_body_bytes;
             }
         }
     } else {
@@ -599,3 +5894,717 @@ fn transform(raw: TransformArgs) -> HttpResponse {
     ic_cdk::println!("res: {:?}", res);
     res
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_cdk::api::management_canister::http_request::TransformFunc;
+
+    fn found(url: &str, match_type: MatchType) -> Match {
+        Match {
+            url: url.to_string(),
+            page_url: None,
+            page_title: None,
+            thumbnail_url: None,
+            match_type,
+            score: None,
+            first_seen: 0,
+            last_seen: 0,
+            domain: url_domain(url),
+            sources: Vec::new(),
+        }
+    }
+
+    fn provider_result(provider: DetectionProvider, matches: Vec<Match>) -> CrawlResult {
+        CrawlResult {
+            prediction_id: String::new(),
+            web_entities: Vec::new(),
+            full_matching_images: Vec::new(),
+            pages_with_matching_images: Vec::new(),
+            visually_similar_images: Vec::new(),
+            last_update: 1,
+            consent_revoked_at: None,
+            source: Some(provider),
+            provider_job_id: None,
+            sources: None,
+            matches: Some(matches),
+        }
+    }
+
+    fn principal(id: u8) -> Principal {
+        Principal::from_slice(&[id])
+    }
+
+    fn hex(hash: [u8; 32]) -> String {
+        hash.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    #[test]
+    fn canonical_url_normalizes_scheme_host_and_port() {
+        assert_eq!(canonical_url("http://Example.com/a.jpg"), "https://example.com/a.jpg");
+        assert_eq!(canonical_url("https://www.example.com/a.jpg"), "https://example.com/a.jpg");
+        assert_eq!(canonical_url("https://example.com:443/a.jpg"), "https://example.com/a.jpg");
+        assert_eq!(canonical_url("http://example.com:80"), "https://example.com/");
+        assert_eq!(canonical_url("https://example.com:8443/a"), "https://example.com:8443/a");
+    }
+
+    #[test]
+    fn canonical_url_drops_fragment_and_tracking_params() {
+        assert_eq!(canonical_url("https://example.com/a#top"), "https://example.com/a");
+        assert_eq!(
+            canonical_url("https://example.com/a?utm_source=x&id=7&fbclid=y&utm_medium=z#top"),
+            "https://example.com/a?id=7"
+        );
+        assert_eq!(canonical_url("https://example.com/?utm_campaign=x"), "https://example.com/");
+    }
+
+    #[test]
+    fn canonical_url_keeps_other_urls() {
+        assert_eq!(canonical_url("ftp://Example.com/a"), "ftp://Example.com/a");
+        assert_eq!(canonical_url("not a url"), "not a url");
+    }
+
+    #[test]
+    fn merge_crawl_results_deduplicates_by_canonical_url() {
+        let merged = merge_crawl_results(vec![
+            provider_result(
+                DetectionProvider::ShootifyProxy,
+                vec![
+                    found("https://www.example.com/a.jpg?utm_source=x", MatchType::PageMatch),
+                    found("https://other.com/b.jpg", MatchType::VisuallySimilar),
+                ],
+            ),
+            provider_result(
+                DetectionProvider::TinEye,
+                vec![found("http://example.com/a.jpg", MatchType::FullMatch)],
+            ),
+        ]);
+
+        let matches = merged.matches.unwrap();
+        assert_eq!(matches.len(), 2);
+        assert_eq!(matches[0].url, "https://example.com/a.jpg");
+        assert_eq!(
+            matches[0].sources,
+            vec![DetectionProvider::ShootifyProxy, DetectionProvider::TinEye]
+        );
+        assert_eq!(
+            merged.sources,
+            Some(vec![DetectionProvider::ShootifyProxy, DetectionProvider::TinEye])
+        );
+        assert_eq!(merged.full_matching_images, vec!["https://example.com/a.jpg"]);
+        assert_eq!(merged.visually_similar_images, vec!["https://other.com/b.jpg"]);
+    }
+
+    #[test]
+    fn merge_crawl_results_keeps_strongest_match_type() {
+        let merged = merge_crawl_results(vec![
+            provider_result(
+                DetectionProvider::Bing,
+                vec![found("https://example.com/a.jpg", MatchType::VisuallySimilar)],
+            ),
+            provider_result(
+                DetectionProvider::TinEye,
+                vec![found("https://example.com/a.jpg", MatchType::FullMatch)],
+            ),
+            provider_result(
+                DetectionProvider::ShootifyProxy,
+                vec![found("https://example.com/a.jpg", MatchType::PageMatch)],
+            ),
+        ]);
+
+        assert_eq!(merged.matches.unwrap()[0].match_type, MatchType::FullMatch);
+    }
+
+    #[test]
+    fn merge_crawl_results_scores_by_share_of_providers() {
+        let merged = merge_crawl_results(vec![
+            provider_result(
+                DetectionProvider::ShootifyProxy,
+                vec![found("https://once.com/a.jpg", MatchType::FullMatch)],
+            ),
+            provider_result(
+                DetectionProvider::TinEye,
+                vec![
+                    found("https://twice.com/a.jpg", MatchType::FullMatch),
+                    found("https://thrice.com/a.jpg", MatchType::FullMatch),
+                ],
+            ),
+            provider_result(
+                DetectionProvider::Bing,
+                vec![
+                    found("https://thrice.com/a.jpg", MatchType::FullMatch),
+                    found("https://twice.com/a.jpg", MatchType::FullMatch),
+                ],
+            ),
+            provider_result(
+                DetectionProvider::ShootifyProxy,
+                vec![found("https://thrice.com/a.jpg", MatchType::FullMatch)],
+            ),
+        ]);
+
+        let scores: Vec<(String, f64)> = merged
+            .matches
+            .unwrap()
+            .into_iter()
+            .map(|found| (found.url, found.score.unwrap()))
+            .collect();
+        assert_eq!(
+            scores,
+            vec![
+                ("https://thrice.com/a.jpg".to_string(), 1.0),
+                ("https://twice.com/a.jpg".to_string(), 2.0 / 3.0),
+                ("https://once.com/a.jpg".to_string(), 1.0 / 3.0),
+            ]
+        );
+    }
+
+    /// Test vectors from the ICRC-3 standard
+    #[test]
+    fn icrc3_value_hash_matches_standard_vectors() {
+        assert_eq!(
+            hex(Icrc3Value::Nat(Nat::from(42u64)).hash()),
+            "684888c0ebb17f374298b65ee2807526c066094c701bcc7ebbe1c1095f494fc1"
+        );
+        assert_eq!(
+            hex(Icrc3Value::Int(Int::from(-42)).hash()),
+            "de5a6f78116eca62d7fc5ce159d23ae6b889b365a1739ad2cf36f925a140d0cc"
+        );
+        assert_eq!(
+            hex(Icrc3Value::Text("Hello, World!".to_string()).hash()),
+            "dffd6021bb2bd5b0af676290809ec3a53191dd81c7f70a4b28688a362182986f"
+        );
+        assert_eq!(
+            hex(Icrc3Value::Blob(vec![1, 2, 3, 4]).hash()),
+            "9f64a747e1b97f131fabb6b447296c9b6f0201e79fb3c5356e6c77e89b6a806a"
+        );
+        let array = Icrc3Value::Array(vec![
+            Icrc3Value::Nat(Nat::from(3u64)),
+            Icrc3Value::Text("foo".to_string()),
+            Icrc3Value::Blob(vec![5, 6]),
+        ]);
+        assert_eq!(
+            hex(array.hash()),
+            "514a04011caa503990d446b7dec5d79e19c221ae607fb08b2848c67734d468d6"
+        );
+        let map = Icrc3Value::Map(vec![
+            (
+                "from".to_string(),
+                Icrc3Value::Blob(vec![
+                    0x00, 0xab, 0xcd, 0xef, 0x00, 0x12, 0x34, 0x00, 0x56, 0x78, 0x9a, 0x00, 0xbc,
+                    0xde, 0xf0, 0x00, 0x01, 0x23, 0x45, 0x67, 0x89, 0x00, 0xab, 0xcd, 0xef, 0x01,
+                ]),
+            ),
+            (
+                "to".to_string(),
+                Icrc3Value::Blob(vec![
+                    0x00, 0xab, 0x0d, 0xef, 0x00, 0x12, 0x34, 0x00, 0x56, 0x78, 0x9a, 0x00, 0xbc,
+                    0xde, 0xf0, 0x00, 0x01, 0x23, 0x45, 0x67, 0x89, 0x00, 0xab, 0xcd, 0xef, 0x01,
+                ]),
+            ),
+            ("amount".to_string(), Icrc3Value::Nat(Nat::from(42u64))),
+            ("created_at".to_string(), Icrc3Value::Nat(Nat::from(1_699_218_263u64))),
+            ("memo".to_string(), Icrc3Value::Nat(Nat::from(0u64))),
+        ]);
+        assert_eq!(
+            hex(map.hash()),
+            "c56ece650e1de4269c5bdeff7875949e3e2033f85b2d193c2ff4f7f78bdcfc75"
+        );
+    }
+
+    #[test]
+    fn outcall_cost_follows_ic_pricing() {
+        let request = CanisterHttpRequestArgument {
+            url: "https://example.com".to_string(),
+            max_response_bytes: Some(1_000),
+            method: HttpMethod::POST,
+            headers: vec![HttpHeader {
+                name: "a".to_string(),
+                value: "bc".to_string(),
+            }],
+            body: Some(vec![0; 10]),
+            transform: None,
+        };
+        // 19 URL + 3 header + 10 body bytes on a 13-node subnet
+        let expected = (3_000_000 + 60_000 * 13) * 13 + 400 * 13 * 32 + 800 * 13 * 1_000;
+        assert_eq!(outcall_cost(&request, 13), expected);
+        let expected = (3_000_000 + 60_000 * 34) * 34 + 400 * 34 * 32 + 800 * 34 * 1_000;
+        assert_eq!(outcall_cost(&request, 34), expected);
+        assert_eq!(outcall_cost(&request, u64::MAX), u64::MAX);
+    }
+
+    #[test]
+    fn outcall_cost_counts_transform_and_default_response_limit() {
+        let request = CanisterHttpRequestArgument {
+            url: "https://example.com".to_string(),
+            max_response_bytes: None,
+            method: HttpMethod::GET,
+            headers: Vec::new(),
+            body: None,
+            transform: Some(TransformContext {
+                function: TransformFunc(candid::Func {
+                    principal: Principal::anonymous(),
+                    method: "transform".to_string(),
+                }),
+                context: vec![1, 2, 3],
+            }),
+        };
+        // 19 URL bytes plus the 9-byte method name and 3-byte context
+        let expected = (3_000_000 + 60_000 * 13) * 13
+            + 400 * 13 * 31
+            + 800 * 13 * MAX_RESPONSE_BYTES_LIMIT;
+        assert_eq!(outcall_cost(&request, 13), expected);
+    }
+
+    #[test]
+    fn only_the_linked_principal_may_offer_or_accept_a_transfer() {
+        let (owner, recipient, third_party) = (principal(1), principal(2), principal(3));
+        STABLE_PRINCIPALS.with_borrow_mut(|principals| {
+            principals.insert("owner".to_string(), owner);
+            principals.insert("recipient".to_string(), recipient);
+        });
+
+        // offer_transfer acts for the sender, accept_transfer for the recipient
+        assert!(may_act_for(&owner, false, "owner"));
+        assert!(may_act_for(&recipient, false, "recipient"));
+        assert!(!may_act_for(&third_party, false, "owner"));
+        assert!(!may_act_for(&third_party, false, "recipient"));
+        assert!(!may_act_for(&recipient, false, "owner"));
+        assert!(!may_act_for(&third_party, false, "unlinked"));
+        assert!(may_act_for(&third_party, true, "owner"));
+    }
+
+    #[test]
+    fn org_members_may_only_read() {
+        assert!(OrgRole::Admin.allows(Permission::Read));
+        assert!(OrgRole::Admin.allows(Permission::Crawl));
+        assert!(OrgRole::Admin.allows(Permission::ManageCases));
+        assert!(OrgRole::Member.allows(Permission::Read));
+        assert!(!OrgRole::Member.allows(Permission::Crawl));
+        assert!(!OrgRole::Member.allows(Permission::ManageCases));
+    }
+
+    fn stored_image(owner: &str, bytes: usize) -> StoredImage {
+        StoredImage {
+            content: vec![0; bytes],
+            prediction_id: String::new(),
+            uploaded_by: owner.to_string(),
+            last_error: None,
+            subject_id: None,
+        }
+    }
+
+    #[test]
+    fn storage_quota_uses_tracked_usage() {
+        let quota = Quota {
+            max_images: Some(2),
+            max_bytes: Some(100),
+            max_crawls_per_day: None,
+        };
+        update_user_account("model", |account| account.quota = Some(quota));
+        let (first, second) = (stored_image("model", 40), stored_image("model", 50));
+        track_image_storage(&first, true);
+        track_image_storage(&second, true);
+
+        assert!(ensure_storage_quota("model", 1, 0).is_err());
+        track_image_storage(&first, false);
+        assert!(ensure_storage_quota("model", 1, 50).is_ok());
+        assert!(ensure_storage_quota("model", 1, 51).is_err());
+        // Users without a storage quota are never refused
+        assert!(ensure_storage_quota("other", 1_000, u64::MAX / 2).is_ok());
+    }
+
+    #[test]
+    fn only_the_consent_signer_may_manage_a_subject() {
+        let (model, third_party) = (principal(1), principal(2));
+        STABLE_CONSENTS.with_borrow_mut(|consents| {
+            consents.insert(
+                "subject".to_string(),
+                ConsentRecord {
+                    subject_id: "subject".to_string(),
+                    model_principal: model.to_text(),
+                    scopes: Vec::new(),
+                    brands: Vec::new(),
+                    signed_at: 0,
+                    expires_at: None,
+                    revoked_at: Some(1),
+                },
+            )
+        });
+
+        assert!(may_manage_subject(&model, false, "subject"));
+        assert!(!may_manage_subject(&third_party, false, "subject"));
+        assert!(may_manage_subject(&third_party, true, "subject"));
+        assert!(!may_manage_subject(&model, false, "unknown"));
+    }
+
+    #[test]
+    fn revoked_hashes_are_never_unlinked() {
+        let entry = SubjectImageEntry {
+            revoked_at: Some(1),
+            ..SubjectImageEntry::legacy("hash".to_string())
+        };
+        STABLE_SUBJECT_IMAGES.with_borrow_mut(|subject_images| {
+            subject_images.insert("subject".to_string(), SubjectImages { entries: vec![entry] })
+        });
+
+        assert!(unlink_subject_hash("subject", "hash").is_none());
+        assert!(ensure_not_revoked("subject", "hash").is_err());
+        assert_eq!(subject_hash_revoked_at("subject", "hash"), Some(1));
+    }
+
+    fn queued_job(id: u64) -> CrawlJob {
+        CrawlJob {
+            id,
+            user_id: "model".to_string(),
+            prediction_id: "prediction".to_string(),
+            image_name: "image.png".to_string(),
+            status: CrawlJobStatus::Queued,
+            result: None,
+            error: None,
+            enqueued_at: 0,
+            started_at: None,
+            finished_at: None,
+            attempts: Some(0),
+            retry_at: None,
+            provider: None,
+            providers: None,
+        }
+    }
+
+    #[test]
+    fn active_crawl_job_index_follows_status() {
+        for id in 0..2 {
+            STABLE_CRAWL_JOBS.with_borrow_mut(|jobs| jobs.insert(id, queued_job(id)));
+        }
+        rebuild_active_crawl_jobs();
+        let active = || STABLE_ACTIVE_CRAWL_JOBS.with_borrow(|a| a.keys().collect::<Vec<_>>());
+        assert_eq!(active(), vec![0, 1]);
+
+        update_crawl_job(0, |job| job.status = CrawlJobStatus::Running);
+        update_crawl_job(1, |job| job.status = CrawlJobStatus::Failed);
+        assert_eq!(active(), vec![0]);
+        update_crawl_job(0, |job| job.status = CrawlJobStatus::Succeeded);
+        assert!(active().is_empty());
+    }
+
+    #[test]
+    fn crawl_credits_are_refunded_for_failed_providers() {
+        let config = Config { payment_ledger: Some(principal(9)), ..Config::default() };
+        STABLE_CONFIG.with_borrow_mut(|cell| cell.set(config)).unwrap();
+        set_crawl_credits("model", 3);
+
+        let detection = Detection { response: "{}".to_string(), failed_providers: 2 };
+        let crawl = async { Ok::<_, SentinelError>(detection) };
+        let response = futures::executor::block_on(with_crawl_credit("model", 3, crawl));
+        assert_eq!(response.unwrap(), "{}");
+        assert_eq!(crawl_credits("model"), 2);
+
+        let crawl = async { Err::<Detection, _>(SentinelError::internal("failed")) };
+        assert!(futures::executor::block_on(with_crawl_credit("model", 2, crawl)).is_err());
+        assert_eq!(crawl_credits("model"), 2);
+    }
+
+    #[test]
+    fn trash_purge_at_saturates() {
+        assert_eq!(trash_purge_at(5, 2), 5 + 2 * NANOS_PER_SEC);
+        let longest = 5 + MAX_TRASH_RETENTION_SECS * NANOS_PER_SEC;
+        assert_eq!(trash_purge_at(5, MAX_TRASH_RETENTION_SECS), longest);
+        assert_eq!(trash_purge_at(5, u64::MAX / 2), u64::MAX);
+        assert_eq!(trash_purge_at(u64::MAX - 1, 1), u64::MAX);
+    }
+
+    #[test]
+    fn matched_domains_skip_visually_similar_images() {
+        let result = provider_result(
+            DetectionProvider::Bing,
+            vec![
+                found("https://a.com/1.jpg", MatchType::FullMatch),
+                found("https://www.a.com/2.jpg", MatchType::PageMatch),
+                found("https://b.com/3.jpg", MatchType::VisuallySimilar),
+            ],
+        );
+        let domains = matched_domains(&result);
+        assert_eq!(domains.len(), 1);
+        assert_eq!(domains.get("a.com"), Some(&2));
+    }
+
+    #[test]
+    fn top_offending_domains_follow_current_consent() {
+        let consent = |subject_id: &str| ConsentRecord {
+            subject_id: subject_id.to_string(),
+            model_principal: principal(1).to_text(),
+            scopes: Vec::new(),
+            brands: Vec::new(),
+            signed_at: 0,
+            expires_at: None,
+            revoked_at: None,
+        };
+        let images = [("one.png", "alice"), ("two.png", "alice"), ("three.png", "bob")];
+        for (name, subject_id) in images {
+            let image = StoredImage {
+                subject_id: Some(subject_id.to_string()),
+                ..stored_image("agency", 1)
+            };
+            STABLE_IMAGES.with_borrow_mut(|images| images.insert(name.to_string(), image));
+            STABLE_CONSENTS.with_borrow_mut(|consents| {
+                consents.insert(subject_id.to_string(), consent(subject_id))
+            });
+            let mut result = provider_result(
+                DetectionProvider::Bing,
+                vec![found(&format!("https://a.com/{}", name), MatchType::FullMatch)],
+            );
+            result.prediction_id = name.to_string();
+            index_crawl_domains(&format!("agency:{}", name), &result);
+        }
+
+        let stats = &top_offending_domains(None)[0];
+        assert_eq!((stats.images, stats.models, stats.matches), (3, 2, 3));
+        assert_eq!(stats.unauthorized_matches, 0);
+
+        let revoked = ConsentRecord { revoked_at: Some(5), ..consent("bob") };
+        STABLE_CONSENTS.with_borrow_mut(|consents| consents.insert("bob".to_string(), revoked));
+        assert_eq!(top_offending_domains(None)[0].unauthorized_matches, 1);
+    }
+
+    #[test]
+    fn subject_scope_covers_only_that_subjects_images() {
+        for (name, subject_id) in [("alice.png", "alice"), ("bob.png", "bob")] {
+            let image = StoredImage {
+                subject_id: Some(subject_id.to_string()),
+                ..stored_image("agency", 1)
+            };
+            STABLE_IMAGES.with_borrow_mut(|images| images.insert(name.to_string(), image));
+        }
+        let grant = |scope| AccessGrant {
+            id: 0,
+            owner_user_id: "agency".to_string(),
+            grantee: "counsel".to_string(),
+            scope,
+            permissions: vec![Permission::Read],
+            granted_by: principal(1).to_text(),
+            created_at: 0,
+            expires_at: None,
+            revoked_at: None,
+        };
+
+        let subject = grant(ContentScope::Subject("alice".to_string()));
+        assert!(subject.covers("agency", "alice.png"));
+        assert!(!subject.covers("agency", "bob.png"));
+        assert!(!subject.covers("other", "alice.png"));
+        assert_eq!(subject_image_names("agency", "alice"), vec!["alice.png".to_string()]);
+        assert!(!grant(ContentScope::Portfolio).covers("agency", "alice.png"));
+        assert!(ensure_supported_scope(&ContentScope::Portfolio).is_err());
+    }
+
+    #[test]
+    fn outcalls_resolved_on_first_poll_were_not_sent() {
+        let not_sent = async { Err::<(), _>((RejectionCode::SysTransient, String::new())) };
+        let (sent, result) = futures::executor::block_on(await_outcall(not_sent));
+        assert!(!sent);
+        assert!(result.is_err());
+
+        let rejected = async {
+            let mut yielded = false;
+            futures::future::poll_fn(|cx| {
+                if std::mem::replace(&mut yielded, true) {
+                    return std::task::Poll::Ready(());
+                }
+                cx.waker().wake_by_ref();
+                std::task::Poll::Pending
+            })
+            .await;
+            Err::<(), _>((RejectionCode::SysTransient, String::new()))
+        };
+        let (sent, result) = futures::executor::block_on(await_outcall(rejected));
+        assert!(sent);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn tineye_matches_come_from_backlinks() {
+        let body = serde_json::json!({
+            "results": {
+                "matches": [{
+                    "score": 0.9,
+                    "image_url": "https://img.tineye.com/thumb.jpg",
+                    "backlinks": [
+                        {"url": "https://a.com/copy.jpg", "backlink": "https://a.com/post"},
+                        {"backlink": "https://b.com/no-image"}
+                    ]
+                }]
+            }
+        });
+        let normalized = TinEyeSearch.normalize(&body).unwrap();
+        let matches = normalized["matches"].as_array().unwrap();
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0]["url"], "https://a.com/copy.jpg");
+        assert_eq!(matches[0]["page_url"], "https://a.com/post");
+        assert_eq!(matches[0]["thumbnail_url"], "https://img.tineye.com/thumb.jpg");
+        assert_eq!(matches[0]["score"], 0.9);
+        assert_eq!(matches[0]["match_type"], serde_json::json!(MatchType::FullMatch));
+        assert!(TinEyeSearch.normalize(&serde_json::json!({"error": "quota"})).is_none());
+    }
+
+    #[test]
+    fn bing_actions_map_to_match_types() {
+        let value = |url: &str| serde_json::json!({"data": {"value": [{
+            "contentUrl": url,
+            "hostPageUrl": "https://a.com/post",
+            "name": "Post",
+        }]}});
+        let mut pages = value("https://a.com/copy.jpg");
+        pages["actionType"] = "PagesIncluding".into();
+        let mut similar = value("https://b.com/alike.jpg");
+        similar["actionType"] = "VisuallySimilarImages".into();
+        let mut other = value("https://c.com/shop.jpg");
+        other["actionType"] = "ShoppingSources".into();
+        let body = serde_json::json!({"tags": [
+            {"displayName": "Model", "actions": [pages, similar]},
+            {"displayName": "", "actions": [other]}
+        ]});
+
+        let normalized = BingSearch.normalize(&body).unwrap();
+        assert_eq!(normalized["web_entities"], serde_json::json!(["Model"]));
+        let matches = normalized["matches"].as_array().unwrap();
+        assert_eq!(matches.len(), 2);
+        assert_eq!(matches[0]["match_type"], serde_json::json!(MatchType::FullMatch));
+        assert_eq!(matches[0]["page_title"], "Post");
+        assert_eq!(matches[1]["url"], "https://b.com/alike.jpg");
+        assert_eq!(matches[1]["match_type"], serde_json::json!(MatchType::VisuallySimilar));
+    }
+
+    #[test]
+    fn sort_json_arrays_orders_and_deduplicates_nested_arrays() {
+        let mut first = serde_json::json!({
+            "matches": [{"url": "b", "sources": ["Bing", "TinEye"]}, {"url": "a"}, {"url": "a"}],
+            "web_entities": ["z", "y"],
+        });
+        let mut second = serde_json::json!({
+            "web_entities": ["y", "z", "z"],
+            "matches": [{"url": "a"}, {"sources": ["TinEye", "Bing"], "url": "b"}],
+        });
+        sort_json_arrays(&mut first);
+        sort_json_arrays(&mut second);
+        assert_eq!(first.to_string(), second.to_string());
+        assert_eq!(first["web_entities"], serde_json::json!(["y", "z"]));
+    }
+
+    #[test]
+    fn cycles_beyond_the_balance_become_overdraft_settled_by_credits() {
+        let mut account = CyclesAccount { balance: 100, ..CyclesAccount::default() };
+        account.debit(40);
+        assert_eq!((account.balance, account.overdraft), (60, None));
+
+        account.debit(90);
+        assert_eq!((account.balance, account.overdraft), (0, Some(30)));
+
+        account.credit(20);
+        assert_eq!((account.balance, account.overdraft), (0, Some(10)));
+        account.credit(25);
+        assert_eq!((account.balance, account.overdraft), (15, None));
+    }
+
+    #[test]
+    fn match_history_marks_urls_gone_and_back() {
+        let crawl = |provider, urls: &[&str], now| {
+            let matches = urls
+                .iter()
+                .map(|url| Match { sources: vec![provider], ..found(url, MatchType::FullMatch) })
+                .collect();
+            let mut result = provider_result(provider, matches);
+            record_match_history("model", "image.png", &mut result, now);
+            result
+        };
+        let history = || {
+            let key = "model:image.png".to_string();
+            STABLE_MATCH_HISTORY.with_borrow(|histories| histories.get(&key)).unwrap().urls
+        };
+
+        crawl(DetectionProvider::Bing, &["https://a.com/1.jpg", "https://b.com/2.jpg"], 10);
+        // Another provider not reporting a URL does not make it gone
+        crawl(DetectionProvider::TinEye, &[], 20);
+        assert!(history().iter().all(|seen| seen.gone_since.is_none()));
+
+        crawl(DetectionProvider::Bing, &["https://www.a.com/1.jpg"], 30);
+        let urls = history();
+        assert_eq!((urls[0].times_seen, urls[0].last_seen, urls[0].gone_since), (2, 30, None));
+        assert_eq!((urls[1].times_seen, urls[1].gone_since), (1, Some(30)));
+
+        let result = crawl(DetectionProvider::Bing, &["https://b.com/2.jpg"], 40);
+        let urls = history();
+        assert_eq!((urls[1].times_seen, urls[1].first_seen, urls[1].gone_since), (2, 10, None));
+        assert_eq!(urls[0].gone_since, Some(40));
+        assert_eq!(result.matches.unwrap()[0].first_seen, 10);
+    }
+
+    #[test]
+    fn consent_is_active_until_it_expires_or_is_revoked() {
+        let consent = ConsentRecord {
+            subject_id: "alice".to_string(),
+            model_principal: principal(1).to_text(),
+            scopes: Vec::new(),
+            brands: Vec::new(),
+            signed_at: 0,
+            expires_at: None,
+            revoked_at: None,
+        };
+        assert!(consent.is_active(u64::MAX));
+
+        let expiring = ConsentRecord { expires_at: Some(100), ..consent.clone() };
+        assert!(expiring.is_active(99));
+        assert!(!expiring.is_active(100));
+
+        let revoked = ConsentRecord { revoked_at: Some(50), ..consent };
+        assert!(!revoked.is_active(10));
+    }
+
+    #[test]
+    fn image_grants_cover_one_image_until_they_lapse() {
+        let grant = AccessGrant {
+            id: 0,
+            owner_user_id: "model".to_string(),
+            grantee: "counsel".to_string(),
+            scope: ContentScope::Image("image.png".to_string()),
+            permissions: vec![Permission::Read],
+            granted_by: principal(1).to_text(),
+            created_at: 0,
+            expires_at: Some(100),
+            revoked_at: None,
+        };
+        assert!(grant.covers("model", "image.png"));
+        assert!(!grant.covers("model", "other.png"));
+        assert!(!grant.covers("other", "image.png"));
+
+        assert!(grant.is_active(99));
+        assert!(!grant.is_active(100));
+        let revoked = AccessGrant { expires_at: None, revoked_at: Some(5), ..grant };
+        assert!(!revoked.is_active(0));
+    }
+
+    #[test]
+    fn purge_drops_only_expired_trash_and_its_storage() {
+        for (name, purge_at) in [("old.png", 100), ("new.png", 200)] {
+            let image = stored_image("model", 10);
+            track_image_storage(&image, true);
+            let trashed = TrashedImage {
+                image,
+                crawl_result: Some(provider_result(DetectionProvider::Bing, Vec::new())),
+                match_history: Some(MatchHistory::default()),
+                subject_hashes: Vec::new(),
+                deleted_at: 0,
+                purge_at,
+            };
+            STABLE_TRASH.with_borrow_mut(|trash| trash.insert(name.to_string(), trashed));
+        }
+
+        purge_trash(150);
+        let names: Vec<String> = STABLE_TRASH.with_borrow(|trash| trash.keys().collect());
+        assert_eq!(names, vec!["new.png".to_string()]);
+        let account = user_account("model");
+        assert_eq!((account.image_count, account.total_bytes), (Some(1), Some(10)));
+
+        purge_trash(200);
+        assert!(STABLE_TRASH.with_borrow(|trash| trash.is_empty()));
+        assert_eq!(user_account("model").image_count, Some(0));
+    }
+}